@@ -0,0 +1,83 @@
+/*
+ * gscreen - A true color command wrapper for terminal programs
+ * Copyright (C) 2025 Gamunu Balagalla <gamunu@fastcode.io>
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program; if not, write to the Free Software Foundation, Inc.,
+ * 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+ */
+
+//! Throughput of the PTY output pipeline (vte parser + `VteHandler`).
+//!
+//! The workload is a recording of vim with `termguicolors` scrolling through
+//! two source files, i.e. dense truecolor SGRs mixed with cursor movement.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::io::{self, Write};
+use std::sync::Arc;
+
+use gscreen::__private::{ChildModes, VteHandler};
+use gscreen::{ColorDepth, ColorMetric, ColorTarget};
+
+const WORKLOAD: &[u8] = include_bytes!("data/vim-truecolor-scroll.raw");
+
+/// Same size as the PTY read buffer in `proxy::run_proxy`
+const READ_CHUNK: usize = 4096;
+
+/// Discards output but goes through `BufWriter` like stdout does
+fn buffered_sink() -> Box<dyn Write + Send> {
+    Box::new(io::BufWriter::with_capacity(64 * 1024, io::sink()))
+}
+
+fn run_pipeline(target: ColorTarget) {
+    let mut parser = vte::Parser::new();
+    let mut handler = VteHandler::new(
+        buffered_sink(),
+        true,
+        target,
+        Arc::new(ChildModes::default()),
+    );
+    for chunk in WORKLOAD.chunks(READ_CHUNK) {
+        handler.process(&mut parser, chunk);
+        handler.flush().unwrap();
+    }
+}
+
+fn bench_vte_pipeline(c: &mut Criterion) {
+    let mut group = c.benchmark_group("vte_pipeline");
+    group.throughput(Throughput::Bytes(WORKLOAD.len() as u64));
+
+    let targets = [
+        (ColorDepth::TrueColor, ColorMetric::Fast),
+        (ColorDepth::Ansi256, ColorMetric::Fast),
+        (ColorDepth::Ansi256, ColorMetric::Ciede2000),
+        (ColorDepth::Ansi16, ColorMetric::Fast),
+    ];
+    for (depth, metric) in targets {
+        let target = ColorTarget {
+            depth,
+            metric,
+            ..ColorTarget::default()
+        };
+        group.bench_with_input(
+            BenchmarkId::new(depth.to_string(), metric),
+            &target,
+            |b, &target| b.iter(|| run_pipeline(target)),
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_vte_pipeline);
+criterion_main!(benches);
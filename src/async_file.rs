@@ -0,0 +1,173 @@
+/*
+ * gscreen - A true color command wrapper for terminal programs
+ * Copyright (C) 2025 Gamunu Balagalla <gamunu@fastcode.io>
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program; if not, write to the Free Software Foundation, Inc.,
+ * 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+ */
+
+use std::ffi::{CStr, OsStr};
+use std::fs::OpenOptions;
+use std::io;
+use std::os::fd::{AsRawFd, BorrowedFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use tokio::io::unix::AsyncFd;
+
+/// A file descriptor read and written as it becomes ready, so the proxy can
+/// sleep until there is something to do
+pub struct AsyncFile {
+    inner: AsyncFd<OwnedFd>,
+    /// Status flags to put back when done, the file may be shared with the
+    /// shell that started us
+    original_flags: libc::c_int,
+}
+
+impl AsyncFile {
+    /// Register a duplicate of `fd` with tokio. The underlying file is in
+    /// non-blocking mode until this is dropped, which every descriptor
+    /// sharing it will see, so `fd` must be ours alone. Fails for files that
+    /// can't be polled, such as regular files.
+    pub fn new(fd: RawFd) -> io::Result<Self> {
+        // SAFETY: the caller hands us an open descriptor, which is only
+        // borrowed long enough to duplicate it
+        let fd = unsafe { BorrowedFd::borrow_raw(fd) }.try_clone_to_owned()?;
+        Self::from_owned(fd)
+    }
+
+    /// Open the terminal on `fd` a second time and register that instead.
+    /// A terminal's stdin, stdout and stderr normally share one open file,
+    /// and making it non-blocking would make writes to stdout fail with
+    /// `EAGAIN`. Fails if `fd` isn't a terminal.
+    pub fn reopen_terminal(fd: RawFd) -> io::Result<Self> {
+        let tty = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(terminal_path(fd)?)?;
+        Self::from_owned(tty.into())
+    }
+
+    fn from_owned(fd: OwnedFd) -> io::Result<Self> {
+        let inner = AsyncFd::new(fd)?;
+
+        let raw_fd = inner.as_raw_fd();
+        let original_flags = cvt(unsafe { libc::fcntl(raw_fd, libc::F_GETFL) })?;
+        cvt(unsafe { libc::fcntl(raw_fd, libc::F_SETFL, original_flags | libc::O_NONBLOCK) })?;
+
+        Ok(Self {
+            inner,
+            original_flags,
+        })
+    }
+
+    /// Read whatever is available, waiting until something is
+    pub async fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let mut guard = self.inner.readable().await?;
+            let result = guard.try_io(|fd| {
+                let n = unsafe { libc::read(fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+                cvt(n)
+            });
+            if let Ok(result) = result {
+                return result.map(|n| n as usize);
+            }
+        }
+    }
+
+    /// Write as much of `buf` as fits, waiting until something does
+    pub async fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        loop {
+            let mut guard = self.inner.writable().await?;
+            let result = guard.try_io(|fd| {
+                let n = unsafe { libc::write(fd.as_raw_fd(), buf.as_ptr().cast(), buf.len()) };
+                cvt(n)
+            });
+            if let Ok(result) = result {
+                return result.map(|n| n as usize);
+            }
+        }
+    }
+}
+
+impl Drop for AsyncFile {
+    fn drop(&mut self) {
+        unsafe { libc::fcntl(self.inner.as_raw_fd(), libc::F_SETFL, self.original_flags) };
+    }
+}
+
+/// The device file of the terminal on `fd`, like `/dev/pts/3`
+fn terminal_path(fd: RawFd) -> io::Result<PathBuf> {
+    let mut name = [0 as libc::c_char; 256];
+    let ret = unsafe { libc::ttyname_r(fd, name.as_mut_ptr(), name.len()) };
+    if ret != 0 {
+        return Err(io::Error::from_raw_os_error(ret));
+    }
+    // SAFETY: ttyname_r succeeded, so `name` holds a NUL terminated string
+    let name = unsafe { CStr::from_ptr(name.as_ptr()) };
+    Ok(PathBuf::from(OsStr::from_bytes(name.to_bytes())))
+}
+
+/// Turn a libc return value into an `io::Result`
+fn cvt<T: Default + PartialOrd>(ret: T) -> io::Result<T> {
+    if ret < T::default() {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pipe() -> (RawFd, RawFd) {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        (fds[0], fds[1])
+    }
+
+    #[tokio::test]
+    async fn test_pipe_round_trip_restores_flags() {
+        let (read_fd, write_fd) = pipe();
+        let flags = unsafe { libc::fcntl(read_fd, libc::F_GETFL) };
+
+        {
+            let reader = AsyncFile::new(read_fd).unwrap();
+            let writer = AsyncFile::new(write_fd).unwrap();
+            assert_ne!(unsafe { libc::fcntl(read_fd, libc::F_GETFL) }, flags);
+
+            assert_eq!(writer.write(b"hello").await.unwrap(), 5);
+            let mut buf = [0u8; 16];
+            let n = reader.read(&mut buf).await.unwrap();
+            assert_eq!(&buf[..n], b"hello");
+        }
+
+        assert_eq!(unsafe { libc::fcntl(read_fd, libc::F_GETFL) }, flags);
+        unsafe {
+            libc::close(read_fd);
+            libc::close(write_fd);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reopen_terminal_refuses_other_files() {
+        let (read_fd, write_fd) = pipe();
+        assert!(AsyncFile::reopen_terminal(read_fd).is_err());
+        unsafe {
+            libc::close(read_fd);
+            libc::close(write_fd);
+        }
+    }
+}
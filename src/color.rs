@@ -19,6 +19,238 @@
 
 /// Color conversion utilities for translating 24-bit RGB to 256-color palette
 use std::cmp;
+use std::fmt;
+use std::sync::OnceLock;
+
+/// Levels used by each channel of the 6x6x6 color cube (colors 16-231)
+const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+/// xterm's default values for the 16 ANSI colors (colors 0-15)
+const ANSI_COLORS: [(u8, u8, u8); 16] = [
+    (0x00, 0x00, 0x00),
+    (0xcd, 0x00, 0x00),
+    (0x00, 0xcd, 0x00),
+    (0xcd, 0xcd, 0x00),
+    (0x00, 0x00, 0xee),
+    (0xcd, 0x00, 0xcd),
+    (0x00, 0xcd, 0xcd),
+    (0xe5, 0xe5, 0xe5),
+    (0x7f, 0x7f, 0x7f),
+    (0xff, 0x00, 0x00),
+    (0x00, 0xff, 0x00),
+    (0xff, 0xff, 0x00),
+    (0x5c, 0x5c, 0xff),
+    (0xff, 0x00, 0xff),
+    (0x00, 0xff, 0xff),
+    (0xff, 0xff, 0xff),
+];
+
+/// Distance metric used to pick the nearest palette color
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum ColorMetric {
+    /// Quantize each channel directly (fastest, least accurate)
+    #[default]
+    Fast,
+    /// CIE94 color difference in CIELAB space
+    Cie94,
+    /// CIEDE2000 color difference in CIELAB space
+    Ciede2000,
+}
+
+impl fmt::Display for ColorMetric {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ColorMetric::Fast => "fast",
+            ColorMetric::Cie94 => "cie94",
+            ColorMetric::Ciede2000 => "ciede2000",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Number of colors the outer terminal can display
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum ColorDepth {
+    /// 24-bit RGB, colors are passed through untouched
+    #[cfg_attr(feature = "cli", value(name = "truecolor"))]
+    TrueColor,
+    /// The xterm 256-color palette
+    #[default]
+    #[cfg_attr(feature = "cli", value(name = "256"))]
+    Ansi256,
+    /// The 16 ANSI colors (SGR 30-37 and 90-97)
+    #[cfg_attr(feature = "cli", value(name = "16"))]
+    Ansi16,
+    /// The 8 basic ANSI colors (SGR 30-37)
+    #[cfg_attr(feature = "cli", value(name = "8"))]
+    Ansi8,
+    /// No color at all
+    #[cfg_attr(feature = "cli", value(name = "mono"))]
+    Mono,
+}
+
+impl fmt::Display for ColorDepth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ColorDepth::TrueColor => "truecolor",
+            ColorDepth::Ansi256 => "256",
+            ColorDepth::Ansi16 => "16",
+            ColorDepth::Ansi8 => "8",
+            ColorDepth::Mono => "mono",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A color as specified by an SGR sequence
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Color {
+    /// Palette index (38;5;N, or 30-37/90-97 for the first 16)
+    Indexed(u8),
+    /// Direct RGB (38;2;R;G;B)
+    Rgb(u8, u8, u8),
+}
+
+/// Colors reported to the child for OSC 10/11/12 queries when the outer
+/// terminal can't answer them itself
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DefaultColors {
+    pub foreground: (u8, u8, u8),
+    pub background: (u8, u8, u8),
+    pub cursor: (u8, u8, u8),
+}
+
+impl DefaultColors {
+    /// Colors from a `COLORFGBG` theme hint like `15;0` or `15;default;0`,
+    /// which some terminals set to the palette indices of their colors
+    pub fn from_colorfgbg(value: &str) -> Option<Self> {
+        let mut fields = value.split(';');
+        let foreground: u8 = fields.next()?.parse().ok()?;
+        let background: u8 = fields.next_back()?.parse().ok()?;
+        if foreground > 15 || background > 15 {
+            return None;
+        }
+
+        Some(Self {
+            foreground: palette_rgb(foreground),
+            background: palette_rgb(background),
+            cursor: palette_rgb(foreground),
+        })
+    }
+}
+
+impl Default for DefaultColors {
+    fn default() -> Self {
+        Self {
+            foreground: (255, 255, 255),
+            background: (0, 0, 0),
+            cursor: (255, 255, 255),
+        }
+    }
+}
+
+/// The RGB values a terminal shows for colors 0-15, which themes often
+/// redefine. Colors are matched against these when converting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Palette {
+    pub colors: [(u8, u8, u8); 16],
+}
+
+impl Palette {
+    /// xterm's defaults
+    pub const XTERM: Palette = Palette {
+        colors: ANSI_COLORS,
+    };
+
+    /// RGB value of a 256-color palette entry, with these colors for 0-15
+    pub fn rgb(&self, index: u8) -> (u8, u8, u8) {
+        match index {
+            0..=15 => self.colors[index as usize],
+            _ => palette_rgb(index),
+        }
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette::XTERM
+    }
+}
+
+/// Where and how colors should be converted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ColorTarget {
+    pub depth: ColorDepth,
+    pub metric: ColorMetric,
+    /// The target terminal's colors 0-15, if known. Without them 256-color
+    /// conversion stays off those colors and the ANSI depths assume xterm's.
+    pub palette: Option<Palette>,
+}
+
+impl ColorTarget {
+    /// Convert a color so the target terminal can display it.
+    /// Returns `None` when the color should be dropped entirely.
+    pub fn convert(&self, color: Color) -> Option<Color> {
+        match (self.depth, color) {
+            (ColorDepth::Mono, _) => None,
+            (ColorDepth::TrueColor, color) => Some(color),
+            (ColorDepth::Ansi256, Color::Indexed(index)) => Some(Color::Indexed(index)),
+            (ColorDepth::Ansi256, Color::Rgb(r, g, b)) => Some(Color::Indexed(
+                rgb_to_256color_with(r, g, b, self.metric, self.palette.as_ref()),
+            )),
+            (ColorDepth::Ansi16, Color::Indexed(index)) if index < 16 => {
+                Some(Color::Indexed(index))
+            }
+            (ColorDepth::Ansi8, Color::Indexed(index)) if index < 8 => Some(Color::Indexed(index)),
+            (ColorDepth::Ansi8, Color::Indexed(index)) if index < 16 && self.palette.is_none() => {
+                // Bright colors fall back to their normal counterparts
+                Some(Color::Indexed(index % 8))
+            }
+            (depth, Color::Indexed(index)) => {
+                let (r, g, b) = self.palette.unwrap_or_default().rgb(index);
+                Some(Color::Indexed(rgb_to_ansi(r, g, b, depth, self)))
+            }
+            (depth, Color::Rgb(r, g, b)) => Some(Color::Indexed(rgb_to_ansi(r, g, b, depth, self))),
+        }
+    }
+}
+
+/// Convert 24-bit RGB values to a 256-color palette index using the given
+/// metric. Colors 0-15 are only picked when their values are known from
+/// `palette`, and never by the fast metric.
+pub fn rgb_to_256color_with(
+    r: u8,
+    g: u8,
+    b: u8,
+    metric: ColorMetric,
+    palette: Option<&Palette>,
+) -> u8 {
+    let delta_e = match metric {
+        ColorMetric::Fast => return rgb_to_256color(r, g, b),
+        ColorMetric::Cie94 => delta_e_94,
+        ColorMetric::Ciede2000 => delta_e_2000,
+    };
+    match palette {
+        Some(palette) => nearest_palette_index((r, g, b), cube_first_order(), palette, delta_e),
+        None => nearest_palette_index((r, g, b), 16..=255, &Palette::XTERM, delta_e),
+    }
+}
+
+/// Convert 24-bit RGB values to the closest of the 16 (or 8) ANSI colors
+fn rgb_to_ansi(r: u8, g: u8, b: u8, depth: ColorDepth, target: &ColorTarget) -> u8 {
+    let count = if depth == ColorDepth::Ansi8 { 8 } else { 16 };
+    let delta_e = match target.metric {
+        // There is no cheap quantization for the ANSI colors, so the fast
+        // metric is plain euclidean distance in CIELAB (CIE76)
+        ColorMetric::Fast => delta_e_76,
+        ColorMetric::Cie94 => delta_e_94,
+        ColorMetric::Ciede2000 => delta_e_2000,
+    };
+    let palette = target.palette.unwrap_or_default();
+    nearest_palette_index((r, g, b), 0..count, &palette, delta_e)
+}
 
 /// Convert 24-bit RGB values to the closest 256-color palette index
 pub fn rgb_to_256color(r: u8, g: u8, b: u8) -> u8 {
@@ -55,6 +287,209 @@ pub fn rgb_to_256color(r: u8, g: u8, b: u8) -> u8 {
     16 + (36 * r6) + (6 * g6) + b6
 }
 
+/// RGB value of a 256-color palette entry, using xterm defaults for 0-15
+pub fn palette_rgb(index: u8) -> (u8, u8, u8) {
+    match index {
+        0..=15 => ANSI_COLORS[index as usize],
+        16..=231 => {
+            let i = index - 16;
+            (
+                CUBE_LEVELS[(i / 36) as usize],
+                CUBE_LEVELS[(i / 6 % 6) as usize],
+                CUBE_LEVELS[(i % 6) as usize],
+            )
+        }
+        232..=255 => {
+            let level = 8 + 10 * (index - 232);
+            (level, level, level)
+        }
+    }
+}
+
+/// A color in CIELAB space (D65 white point)
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Lab {
+    l: f64,
+    a: f64,
+    b: f64,
+}
+
+impl Lab {
+    fn from_rgb(r: u8, g: u8, b: u8) -> Self {
+        fn linearize(c: u8) -> f64 {
+            let c = c as f64 / 255.0;
+            if c <= 0.04045 {
+                c / 12.92
+            } else {
+                ((c + 0.055) / 1.055).powf(2.4)
+            }
+        }
+
+        fn f(t: f64) -> f64 {
+            const DELTA: f64 = 6.0 / 29.0;
+            if t > DELTA * DELTA * DELTA {
+                t.cbrt()
+            } else {
+                t / (3.0 * DELTA * DELTA) + 4.0 / 29.0
+            }
+        }
+
+        let (r, g, b) = (linearize(r), linearize(g), linearize(b));
+
+        // sRGB -> XYZ, normalized by the D65 reference white
+        let x = (0.4124 * r + 0.3576 * g + 0.1805 * b) / 0.95047;
+        let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+        let z = (0.0193 * r + 0.1192 * g + 0.9505 * b) / 1.08883;
+
+        let (fx, fy, fz) = (f(x), f(y), f(z));
+        Lab {
+            l: 116.0 * fy - 16.0,
+            a: 500.0 * (fx - fy),
+            b: 200.0 * (fy - fz),
+        }
+    }
+}
+
+/// CIELAB values of every palette entry, computed on first use
+fn palette_lab() -> &'static [Lab; 256] {
+    static PALETTE: OnceLock<[Lab; 256]> = OnceLock::new();
+    PALETTE.get_or_init(|| {
+        std::array::from_fn(|i| {
+            let (r, g, b) = palette_rgb(i as u8);
+            Lab::from_rgb(r, g, b)
+        })
+    })
+}
+
+/// Search the cube and grayscale ramp first so they win ties: their values
+/// are fixed, while colors 0-15 are often redefined by the terminal theme
+fn cube_first_order() -> impl Iterator<Item = u8> {
+    (16..=255u8).chain(0..16)
+}
+
+fn nearest_palette_index(
+    (r, g, b): (u8, u8, u8),
+    candidates: impl Iterator<Item = u8>,
+    palette: &Palette,
+    delta_e: fn(&Lab, &Lab) -> f64,
+) -> u8 {
+    let target = Lab::from_rgb(r, g, b);
+    let table = palette_lab();
+    // Only a theme's own colors 0-15 aren't in the precomputed table
+    let lab = |index: u8| {
+        if index < 16 && *palette != Palette::XTERM {
+            let (r, g, b) = palette.colors[index as usize];
+            Lab::from_rgb(r, g, b)
+        } else {
+            table[index as usize]
+        }
+    };
+
+    let mut best = 0u8;
+    let mut best_distance = f64::INFINITY;
+    for index in candidates {
+        let distance = delta_e(&target, &lab(index));
+        if distance < best_distance {
+            best = index;
+            best_distance = distance;
+        }
+    }
+    best
+}
+
+/// CIE76 color difference (euclidean distance in CIELAB)
+fn delta_e_76(lab1: &Lab, lab2: &Lab) -> f64 {
+    ((lab1.l - lab2.l).powi(2) + (lab1.a - lab2.a).powi(2) + (lab1.b - lab2.b).powi(2)).sqrt()
+}
+
+/// CIE94 color difference (graphic arts weighting)
+fn delta_e_94(lab1: &Lab, lab2: &Lab) -> f64 {
+    const K1: f64 = 0.045;
+    const K2: f64 = 0.015;
+
+    let delta_l = lab1.l - lab2.l;
+    let c1 = lab1.a.hypot(lab1.b);
+    let c2 = lab2.a.hypot(lab2.b);
+    let delta_c = c1 - c2;
+    let delta_a = lab1.a - lab2.a;
+    let delta_b = lab1.b - lab2.b;
+    let delta_h_sq = (delta_a * delta_a + delta_b * delta_b - delta_c * delta_c).max(0.0);
+
+    let s_c = 1.0 + K1 * c1;
+    let s_h = 1.0 + K2 * c1;
+
+    (delta_l * delta_l + (delta_c / s_c).powi(2) + delta_h_sq / (s_h * s_h)).sqrt()
+}
+
+/// CIEDE2000 color difference (Sharma, Wu and Dalal formulation)
+fn delta_e_2000(lab1: &Lab, lab2: &Lab) -> f64 {
+    let pow25_7 = 25f64.powi(7);
+
+    let c1 = lab1.a.hypot(lab1.b);
+    let c2 = lab2.a.hypot(lab2.b);
+    let c_bar7 = ((c1 + c2) / 2.0).powi(7);
+    let g = 0.5 * (1.0 - (c_bar7 / (c_bar7 + pow25_7)).sqrt());
+
+    let a1 = lab1.a * (1.0 + g);
+    let a2 = lab2.a * (1.0 + g);
+    let c1 = a1.hypot(lab1.b);
+    let c2 = a2.hypot(lab2.b);
+
+    let hue = |b: f64, a: f64| {
+        if a == 0.0 && b == 0.0 {
+            0.0
+        } else {
+            b.atan2(a).to_degrees().rem_euclid(360.0)
+        }
+    };
+    let h1 = hue(lab1.b, a1);
+    let h2 = hue(lab2.b, a2);
+
+    let delta_l = lab2.l - lab1.l;
+    let delta_c = c2 - c1;
+    let delta_h = if c1 * c2 == 0.0 {
+        0.0
+    } else if (h2 - h1).abs() <= 180.0 {
+        h2 - h1
+    } else if h2 - h1 > 180.0 {
+        h2 - h1 - 360.0
+    } else {
+        h2 - h1 + 360.0
+    };
+    let delta_big_h = 2.0 * (c1 * c2).sqrt() * (delta_h.to_radians() / 2.0).sin();
+
+    let l_bar = (lab1.l + lab2.l) / 2.0;
+    let c_bar = (c1 + c2) / 2.0;
+    let h_bar = if c1 * c2 == 0.0 {
+        h1 + h2
+    } else if (h1 - h2).abs() <= 180.0 {
+        (h1 + h2) / 2.0
+    } else if h1 + h2 < 360.0 {
+        (h1 + h2 + 360.0) / 2.0
+    } else {
+        (h1 + h2 - 360.0) / 2.0
+    };
+
+    let t = 1.0 - 0.17 * (h_bar - 30.0).to_radians().cos()
+        + 0.24 * (2.0 * h_bar).to_radians().cos()
+        + 0.32 * (3.0 * h_bar + 6.0).to_radians().cos()
+        - 0.20 * (4.0 * h_bar - 63.0).to_radians().cos();
+
+    let delta_theta = 30.0 * (-((h_bar - 275.0) / 25.0).powi(2)).exp();
+    let c_bar7 = c_bar.powi(7);
+    let r_c = 2.0 * (c_bar7 / (c_bar7 + pow25_7)).sqrt();
+    let l_term = (l_bar - 50.0).powi(2);
+    let s_l = 1.0 + 0.015 * l_term / (20.0 + l_term).sqrt();
+    let s_c = 1.0 + 0.045 * c_bar;
+    let s_h = 1.0 + 0.015 * c_bar * t;
+    let r_t = -(2.0 * delta_theta).to_radians().sin() * r_c;
+
+    let l = delta_l / s_l;
+    let c = delta_c / s_c;
+    let h = delta_big_h / s_h;
+    (l * l + c * c + h * h + r_t * c * h).sqrt()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -84,4 +519,223 @@ mod tests {
         // Light gray should have higher index than dark gray
         assert!(light_gray > dark_gray);
     }
+
+    #[test]
+    fn test_palette_rgb() {
+        assert_eq!(palette_rgb(1), (0xcd, 0x00, 0x00));
+        assert_eq!(palette_rgb(16), (0, 0, 0));
+        assert_eq!(palette_rgb(110), (135, 175, 215));
+        assert_eq!(palette_rgb(231), (255, 255, 255));
+        assert_eq!(palette_rgb(232), (8, 8, 8));
+        assert_eq!(palette_rgb(255), (238, 238, 238));
+    }
+
+    #[test]
+    fn test_default_colors_from_colorfgbg() {
+        let light = DefaultColors::from_colorfgbg("0;default;15").unwrap();
+        assert_eq!(light.foreground, (0, 0, 0));
+        assert_eq!(light.background, (255, 255, 255));
+        assert_eq!(light.cursor, (0, 0, 0));
+
+        assert_eq!(
+            DefaultColors::from_colorfgbg("7;0").unwrap().background,
+            (0, 0, 0)
+        );
+        assert!(DefaultColors::from_colorfgbg("default").is_none());
+        assert!(DefaultColors::from_colorfgbg("15;200").is_none());
+    }
+
+    #[test]
+    fn test_delta_e_2000_reference_pairs() {
+        // Test data from Sharma, Wu and Dalal (2005), "The CIEDE2000
+        // Color-Difference Formula: Implementation Notes"
+        let cases = [
+            ((50.0, 2.6772, -79.7751), (50.0, 0.0, -82.7485), 2.0425),
+            ((50.0, 3.1571, -77.2803), (50.0, 0.0, -82.7485), 2.8615),
+            ((50.0, 0.0, 0.0), (50.0, -1.0, 2.0), 2.3669),
+            ((50.0, 2.5, 0.0), (73.0, 25.0, -18.0), 27.1492),
+            ((50.0, 2.5, 0.0), (50.0, 0.0, -2.5), 4.3065),
+            (
+                (60.2574, -34.0099, 36.2677),
+                (60.4626, -34.1751, 39.4387),
+                1.2644,
+            ),
+            (
+                (2.0776, 0.0795, -1.1350),
+                (0.9033, -0.0636, -0.5514),
+                0.9082,
+            ),
+        ];
+
+        for ((l1, a1, b1), (l2, a2, b2), expected) in cases {
+            let lab1 = Lab {
+                l: l1,
+                a: a1,
+                b: b1,
+            };
+            let lab2 = Lab {
+                l: l2,
+                a: a2,
+                b: b2,
+            };
+            let delta = delta_e_2000(&lab1, &lab2);
+            assert!(
+                (delta - expected).abs() < 1e-4,
+                "ΔE00 of {:?} and {:?} was {}, expected {}",
+                lab1,
+                lab2,
+                delta,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_perceptual_reference_matches() {
+        // (input, nearest palette index) pairs; every metric in CIELAB space
+        // should agree on these
+        let cases = [
+            // Exact palette hits
+            ((0, 0, 0), 16),
+            ((255, 255, 255), 231),
+            ((255, 0, 0), 196),
+            ((0, 95, 135), 24),
+            ((135, 175, 215), 110),
+            ((255, 215, 0), 220),
+            ((18, 18, 18), 233),
+            // Nearest cube step rather than truncated channel level
+            ((200, 0, 0), 160),
+            ((255, 128, 0), 208),
+            ((70, 130, 180), 67),
+            ((128, 0, 128), 90),
+            // Grays land on the closest ramp entry
+            ((64, 64, 64), 238),
+            ((127, 127, 127), 244),
+            ((128, 128, 128), 244),
+            ((250, 250, 250), 231),
+        ];
+
+        for metric in [ColorMetric::Cie94, ColorMetric::Ciede2000] {
+            for ((r, g, b), expected) in cases {
+                assert_eq!(
+                    rgb_to_256color_with(r, g, b, metric, None),
+                    expected,
+                    "{:?} for ({}, {}, {})",
+                    metric,
+                    r,
+                    g,
+                    b
+                );
+            }
+
+            // Colors 0-15 only once the terminal's values for them are known
+            let xterm = Some(&Palette::XTERM);
+            assert_eq!(rgb_to_256color_with(200, 0, 0, metric, xterm), 1);
+            assert_eq!(rgb_to_256color_with(127, 127, 127, metric, xterm), 8);
+        }
+    }
+
+    #[test]
+    fn test_color_target_downgrades() {
+        let target = |depth| ColorTarget {
+            depth,
+            metric: ColorMetric::Ciede2000,
+            ..ColorTarget::default()
+        };
+
+        let truecolor = target(ColorDepth::TrueColor);
+        assert_eq!(
+            truecolor.convert(Color::Rgb(1, 2, 3)),
+            Some(Color::Rgb(1, 2, 3))
+        );
+
+        let ansi256 = target(ColorDepth::Ansi256);
+        assert_eq!(
+            ansi256.convert(Color::Rgb(255, 0, 0)),
+            Some(Color::Indexed(196))
+        );
+        assert_eq!(
+            ansi256.convert(Color::Indexed(42)),
+            Some(Color::Indexed(42))
+        );
+
+        let ansi16 = target(ColorDepth::Ansi16);
+        assert_eq!(
+            ansi16.convert(Color::Rgb(255, 0, 0)),
+            Some(Color::Indexed(9))
+        );
+        assert_eq!(ansi16.convert(Color::Indexed(196)), Some(Color::Indexed(9)));
+        assert_eq!(ansi16.convert(Color::Indexed(12)), Some(Color::Indexed(12)));
+        assert_eq!(ansi16.convert(Color::Indexed(232)), Some(Color::Indexed(0)));
+
+        let ansi8 = target(ColorDepth::Ansi8);
+        assert_eq!(
+            ansi8.convert(Color::Rgb(255, 0, 0)),
+            Some(Color::Indexed(1))
+        );
+        assert_eq!(ansi8.convert(Color::Indexed(12)), Some(Color::Indexed(4)));
+        assert_eq!(
+            ansi8.convert(Color::Rgb(255, 255, 255)),
+            Some(Color::Indexed(7))
+        );
+
+        let mono = target(ColorDepth::Mono);
+        assert_eq!(mono.convert(Color::Rgb(255, 0, 0)), None);
+        assert_eq!(mono.convert(Color::Indexed(1)), None);
+    }
+
+    #[test]
+    fn test_themed_palette_used_for_ansi_colors() {
+        // A theme whose "red" is closer to orange
+        let mut palette = Palette::XTERM;
+        palette.colors[1] = (0xd0, 0x60, 0x20);
+        palette.colors[9] = (0xf0, 0x80, 0x40);
+        let target = ColorTarget {
+            depth: ColorDepth::Ansi16,
+            metric: ColorMetric::Fast,
+            palette: Some(palette),
+        };
+
+        assert_eq!(
+            target.convert(Color::Rgb(240, 128, 64)),
+            Some(Color::Indexed(9))
+        );
+        assert_eq!(palette.rgb(1), (0xd0, 0x60, 0x20));
+        assert_eq!(palette.rgb(196), palette_rgb(196));
+        let xterm = ColorTarget {
+            palette: None,
+            ..target
+        };
+        assert_ne!(
+            xterm.convert(Color::Rgb(240, 128, 64)),
+            Some(Color::Indexed(9))
+        );
+
+        // Bright colors map to whichever of the theme's first 8 is closest
+        let ansi8 = ColorTarget {
+            depth: ColorDepth::Ansi8,
+            ..target
+        };
+        assert_eq!(ansi8.convert(Color::Indexed(9)), Some(Color::Indexed(1)));
+        palette.colors[9] = palette.colors[3];
+        let ansi8 = ColorTarget {
+            palette: Some(palette),
+            ..ansi8
+        };
+        assert_eq!(ansi8.convert(Color::Indexed(9)), Some(Color::Indexed(3)));
+        let ansi8 = ColorTarget {
+            palette: None,
+            ..ansi8
+        };
+        assert_eq!(ansi8.convert(Color::Indexed(9)), Some(Color::Indexed(1)));
+    }
+
+    #[test]
+    fn test_fast_metric_is_default() {
+        assert_eq!(ColorMetric::default(), ColorMetric::Fast);
+        assert_eq!(
+            rgb_to_256color_with(200, 0, 0, ColorMetric::Fast, None),
+            rgb_to_256color(200, 0, 0)
+        );
+    }
 }
@@ -0,0 +1,373 @@
+/*
+ * gscreen - A true color command wrapper for terminal programs
+ * Copyright (C) 2025 Gamunu Balagalla <gamunu@fastcode.io>
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program; if not, write to the Free Software Foundation, Inc.,
+ * 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+ */
+
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use serde::de::{self, Deserializer};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::probe::ProbeMode;
+use crate::proxy::InputMode;
+use crate::signals::ForwardedSignal;
+use gscreen::color::{ColorDepth, ColorMetric, DefaultColors, Palette};
+
+/// Settings from the config file. Everything is optional, so a profile only
+/// names what it changes and the command line can still override it all.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Settings {
+    #[serde(deserialize_with = "value_enum")]
+    pub color_depth: Option<ColorDepth>,
+    #[serde(deserialize_with = "value_enum")]
+    pub color_metric: Option<ColorMetric>,
+    #[serde(deserialize_with = "value_enum")]
+    pub input_mode: Option<InputMode>,
+    #[serde(deserialize_with = "value_enum_list")]
+    pub forward_signals: Option<Vec<ForwardedSignal>>,
+    #[serde(deserialize_with = "value_enum")]
+    pub probe: Option<ProbeMode>,
+    /// TERM for the child
+    pub term: Option<String>,
+    /// Extra variables for the child's environment
+    pub env: HashMap<String, String>,
+    /// Variables removed from the child's environment
+    pub unset_env: Vec<String>,
+    pub colors: ColorSettings,
+    /// `[profile.<name>]` sections, picked by command name or `--profile`
+    #[serde(rename = "profile")]
+    profiles: HashMap<String, Settings>,
+}
+
+/// The `[colors]` table, answers to OSC 4 and OSC 10/11/12 color queries
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ColorSettings {
+    #[serde(deserialize_with = "hex_color")]
+    pub foreground: Option<(u8, u8, u8)>,
+    #[serde(deserialize_with = "hex_color")]
+    pub background: Option<(u8, u8, u8)>,
+    #[serde(deserialize_with = "hex_color")]
+    pub cursor: Option<(u8, u8, u8)>,
+    /// The terminal theme's colors 0-15
+    #[serde(deserialize_with = "hex_palette")]
+    pub palette: Option<Palette>,
+}
+
+impl ColorSettings {
+    /// The configured colors, with `defaults` for anything left out
+    pub fn default_colors(&self, defaults: DefaultColors) -> DefaultColors {
+        DefaultColors {
+            foreground: self.foreground.unwrap_or(defaults.foreground),
+            background: self.background.unwrap_or(defaults.background),
+            cursor: self.cursor.unwrap_or(defaults.cursor),
+        }
+    }
+}
+
+/// `$XDG_CONFIG_HOME/gscreen/config.toml`, falling back to `~/.config`
+pub fn default_path() -> Option<PathBuf> {
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(config_home.join("gscreen").join("config.toml"))
+}
+
+/// Load the config file at `path`, or at the default path if there is one
+/// there. Only an explicitly given file has to exist.
+pub fn load(path: Option<&Path>) -> Result<(Settings, Option<PathBuf>)> {
+    let path = match path {
+        Some(path) => path.to_path_buf(),
+        None => match default_path() {
+            Some(path) if path.exists() => path,
+            _ => return Ok((Settings::default(), None)),
+        },
+    };
+
+    let text = std::fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read config file {}", path.display()))?;
+    let settings = Settings::parse(&text)
+        .with_context(|| format!("Invalid config file {}", path.display()))?;
+    Ok((settings, Some(path)))
+}
+
+impl Settings {
+    pub fn parse(text: &str) -> Result<Self> {
+        let settings: Settings = toml::from_str(text)?;
+        for (name, profile) in &settings.profiles {
+            if !profile.profiles.is_empty() {
+                bail!("profile.{}.profile: profiles can't be nested", name);
+            }
+        }
+        Ok(settings)
+    }
+
+    /// The global settings with a profile applied on top: the one named by
+    /// `profile`, which must exist, or else the one named after `command`
+    pub fn for_command(mut self, profile: Option<&str>, command: &str) -> Result<Settings> {
+        let command_name = Path::new(command)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or(command);
+
+        let overlay = match profile {
+            Some(name) => match self.profiles.remove(name) {
+                Some(overlay) => Some(overlay),
+                None => bail!("Profile '{}' not found in the config file", name),
+            },
+            None => self.profiles.remove(command_name),
+        };
+
+        self.profiles.clear();
+        if let Some(overlay) = overlay {
+            self.merge(overlay);
+        }
+        Ok(self)
+    }
+
+    fn merge(&mut self, overlay: Settings) {
+        self.color_depth = overlay.color_depth.or(self.color_depth);
+        self.color_metric = overlay.color_metric.or(self.color_metric);
+        self.input_mode = overlay.input_mode.or(self.input_mode);
+        self.forward_signals = overlay.forward_signals.or(self.forward_signals.take());
+        self.probe = overlay.probe.or(self.probe);
+        self.term = overlay.term.or(self.term.take());
+        // A variable the profile sets is no longer unset, as with --env
+        self.unset_env.retain(|key| !overlay.env.contains_key(key));
+        self.env.extend(overlay.env);
+        self.unset_env.extend(overlay.unset_env);
+
+        let colors = overlay.colors;
+        self.colors.foreground = colors.foreground.or(self.colors.foreground);
+        self.colors.background = colors.background.or(self.colors.background);
+        self.colors.cursor = colors.cursor.or(self.colors.cursor);
+        self.colors.palette = colors.palette.or(self.colors.palette);
+    }
+}
+
+/// Parse a value with the same names the command line accepts
+fn parse_value_enum<T: ValueEnum>(name: &str) -> Result<T, String> {
+    T::from_str(name, false).map_err(|_| {
+        let expected: Vec<String> = T::value_variants()
+            .iter()
+            .filter_map(|value| value.to_possible_value())
+            .map(|value| format!("'{}'", value.get_name()))
+            .collect();
+        format!(
+            "invalid value '{}', expected one of {}",
+            name,
+            expected.join(", ")
+        )
+    })
+}
+
+fn value_enum<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: ValueEnum,
+{
+    let name = String::deserialize(deserializer)?;
+    parse_value_enum(&name).map(Some).map_err(de::Error::custom)
+}
+
+fn value_enum_list<'de, D, T>(deserializer: D) -> Result<Option<Vec<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: ValueEnum,
+{
+    let names = Vec::<String>::deserialize(deserializer)?;
+    names
+        .iter()
+        .map(|name| parse_value_enum(name))
+        .collect::<Result<Vec<T>, String>>()
+        .map(Some)
+        .map_err(de::Error::custom)
+}
+
+/// A `#rrggbb` color
+fn hex_color<'de, D>(deserializer: D) -> Result<Option<(u8, u8, u8)>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let text = String::deserialize(deserializer)?;
+    parse_hex_color(&text)
+        .map(Some)
+        .ok_or_else(|| de::Error::custom(format!("invalid color '{}', expected #rrggbb", text)))
+}
+
+/// Sixteen `#rrggbb` colors, for palette entries 0-15
+fn hex_palette<'de, D>(deserializer: D) -> Result<Option<Palette>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let texts = Vec::<String>::deserialize(deserializer)?;
+    let mut palette = Palette::XTERM;
+    if texts.len() != palette.colors.len() {
+        return Err(de::Error::custom(format!(
+            "expected {} colors, got {}",
+            palette.colors.len(),
+            texts.len()
+        )));
+    }
+    for (color, text) in palette.colors.iter_mut().zip(&texts) {
+        *color = parse_hex_color(text).ok_or_else(|| {
+            de::Error::custom(format!("invalid color '{}', expected #rrggbb", text))
+        })?;
+    }
+    Ok(Some(palette))
+}
+
+fn parse_hex_color(text: &str) -> Option<(u8, u8, u8)> {
+    let hex = text.strip_prefix('#')?;
+    if hex.len() != 6 || !hex.is_ascii() {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+    Some((channel(0)?, channel(2)?, channel(4)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: &str = r##"
+color-metric = "cie94"
+term = "xterm-256color"
+forward-signals = ["term", "hup"]
+
+[env]
+FORCE_COLOR = "1"
+PAGER = "less"
+
+[colors]
+background = "#1e1e2e"
+
+[profile.nvim]
+color-metric = "ciede2000"
+env = { PAGER = "nvimpager" }
+
+[profile.htop]
+color-depth = "16"
+unset-env = ["FORCE_COLOR"]
+input-mode = "events"
+colors = { foreground = "#cdd6f4" }
+"##;
+
+    #[test]
+    fn test_profile_applied_over_defaults() {
+        let settings = Settings::parse(SAMPLE).unwrap();
+
+        let nvim = settings.clone().for_command(None, "/usr/bin/nvim").unwrap();
+        assert_eq!(nvim.color_metric, Some(ColorMetric::Ciede2000));
+        assert_eq!(nvim.color_depth, None);
+        assert_eq!(nvim.env["PAGER"], "nvimpager");
+        assert_eq!(nvim.env["FORCE_COLOR"], "1");
+
+        let htop = settings.clone().for_command(None, "htop").unwrap();
+        assert_eq!(htop.color_depth, Some(ColorDepth::Ansi16));
+        assert_eq!(htop.color_metric, Some(ColorMetric::Cie94));
+        assert_eq!(htop.input_mode, Some(InputMode::Events));
+        assert_eq!(htop.unset_env, ["FORCE_COLOR"]);
+        assert_eq!(
+            htop.forward_signals,
+            Some(vec![ForwardedSignal::Term, ForwardedSignal::Hup])
+        );
+        let colors = htop.colors.default_colors(DefaultColors::default());
+        assert_eq!(colors.foreground, (0xcd, 0xd6, 0xf4));
+        assert_eq!(colors.background, (0x1e, 0x1e, 0x2e));
+        assert_eq!(colors.cursor, DefaultColors::default().cursor);
+
+        // --profile picks a profile whatever the command is
+        let forced = settings.clone().for_command(Some("htop"), "vim").unwrap();
+        assert_eq!(forced.color_depth, Some(ColorDepth::Ansi16));
+        assert!(settings.for_command(Some("missing"), "vim").is_err());
+    }
+
+    #[test]
+    fn test_profile_env_overrides_global_unset() {
+        let settings = Settings::parse(
+            "unset-env = [\"PAGER\", \"NO_COLOR\"]\n\
+             [profile.git]\n\
+             env = { PAGER = \"delta\" }",
+        )
+        .unwrap();
+
+        let git = settings.clone().for_command(None, "git").unwrap();
+        assert_eq!(git.env["PAGER"], "delta");
+        assert_eq!(git.unset_env, ["NO_COLOR"]);
+
+        let other = settings.for_command(None, "ls").unwrap();
+        assert!(other.env.is_empty());
+        assert_eq!(other.unset_env, ["PAGER", "NO_COLOR"]);
+    }
+
+    #[test]
+    fn test_configured_palette_answers_osc_4() {
+        use gscreen::__private::{ChildModes, VteHandler};
+        use gscreen::color::ColorTarget;
+        use std::sync::Arc;
+
+        let mut colors: Vec<String> = Palette::XTERM
+            .colors
+            .iter()
+            .map(|(r, g, b)| format!("\"#{:02x}{:02x}{:02x}\"", r, g, b))
+            .collect();
+        colors[1] = "\"#f38ba8\"".to_string();
+        let text = format!("[colors]\npalette = [{}]", colors.join(", "));
+        let settings = Settings::parse(&text)
+            .unwrap()
+            .for_command(None, "vim")
+            .unwrap();
+
+        let target = ColorTarget {
+            palette: settings.colors.palette,
+            ..ColorTarget::default()
+        };
+        let mut handler =
+            VteHandler::new(Vec::new(), false, target, Arc::new(ChildModes::default()));
+        handler.process(&mut vte::Parser::new(), b"\x1b]4;1;?\x07");
+        assert_eq!(
+            String::from_utf8(handler.take_replies()).unwrap(),
+            "\x1b]4;1;rgb:f3f3/8b8b/a8a8\x07"
+        );
+        assert!(handler.into_inner().is_empty());
+    }
+
+    #[test]
+    fn test_errors_name_the_offending_key() {
+        let cases = [
+            ("colour-depth = \"16\"", "colour-depth"),
+            ("color-depth = \"12\"", "invalid value '12'"),
+            (
+                "[profile.htop]\ninput-mode = \"keys\"",
+                "input-mode = \"keys\"",
+            ),
+            ("[colors]\ncursor = \"red\"", "cursor = \"red\""),
+            ("[colors]\npalette = [\"#000000\"]", "expected 16 colors"),
+            ("[profile.a.profile.b]\nterm = \"x\"", "profile.a.profile"),
+        ];
+
+        for (text, expected) in cases {
+            let error = format!("{:#}", Settings::parse(text).unwrap_err());
+            assert!(error.contains(expected), "{:?} gave {}", text, error);
+        }
+    }
+}
@@ -0,0 +1,117 @@
+/*
+ * gscreen - A true color command wrapper for terminal programs
+ * Copyright (C) 2025 Gamunu Balagalla <gamunu@fastcode.io>
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program; if not, write to the Free Software Foundation, Inc.,
+ * 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+ */
+
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+use vte::Parser;
+
+use gscreen::__private::{ChildModes, VteHandler};
+use gscreen::color::ColorTarget;
+
+/// Size of each read from the input
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Stream `input` to `output` with colors converted to `color_target`.
+/// Output is flushed after every read so a filter in a pipeline shows
+/// lines as they come. A reader that went away, like `head`, is no error.
+pub fn convert(
+    mut input: impl Read,
+    output: Box<dyn Write + Send>,
+    color_target: ColorTarget,
+) -> io::Result<()> {
+    // Queries are kept as they are, there is nobody here to answer them
+    let modes = Arc::new(ChildModes::default());
+    let mut handler = VteHandler::new(output, true, color_target, modes);
+    let mut parser = Parser::new();
+    let mut buffer = vec![0u8; READ_BUFFER_SIZE];
+
+    let result = loop {
+        let n = match input.read(&mut buffer) {
+            Ok(0) => break Ok(()),
+            Ok(n) => n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => break Err(err),
+        };
+        handler.process(&mut parser, &buffer[..n]);
+        if let Err(err) = handler.flush() {
+            break Err(err);
+        }
+    };
+
+    match result {
+        Err(err) if err.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        result => result,
+    }
+}
+
+/// Convert between files, `None` or `-` meaning stdin and stdout
+pub fn convert_files(
+    input: Option<&Path>,
+    output: Option<&Path>,
+    color_target: ColorTarget,
+) -> Result<()> {
+    let input: Box<dyn Read> = match input.filter(|path| *path != Path::new("-")) {
+        Some(path) => Box::new(
+            File::open(path).with_context(|| format!("Failed to open {}", path.display()))?,
+        ),
+        None => Box::new(io::stdin().lock()),
+    };
+    let output: Box<dyn Write + Send> = match output.filter(|path| *path != Path::new("-")) {
+        Some(path) => {
+            Box::new(BufWriter::new(File::create(path).with_context(|| {
+                format!("Failed to create {}", path.display())
+            })?))
+        }
+        None => Box::new(BufWriter::new(io::stdout())),
+    };
+
+    convert(input, output, color_target).context("Failed to convert colors")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gscreen::color::{ColorDepth, ColorMetric};
+
+    #[test]
+    fn test_file_converted_to_256_colors() {
+        let dir = std::env::temp_dir();
+        let input = dir.join(format!("gscreen-convert-in-{}", std::process::id()));
+        let output = dir.join(format!("gscreen-convert-out-{}", std::process::id()));
+        std::fs::write(&input, b"\x1b[38;2;255;0;0mred\x1b[m \x1b]11;?\x07plain\n").unwrap();
+
+        let target = ColorTarget {
+            depth: ColorDepth::Ansi256,
+            metric: ColorMetric::Fast,
+            ..ColorTarget::default()
+        };
+        convert_files(Some(&input), Some(&output), target).unwrap();
+        let converted = std::fs::read(&output).unwrap();
+        let _ = std::fs::remove_file(&input);
+        let _ = std::fs::remove_file(&output);
+
+        assert_eq!(
+            converted,
+            b"\x1b[38;5;196mred\x1b[m \x1b]11;?\x07plain\n".to_vec()
+        );
+    }
+}
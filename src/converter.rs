@@ -0,0 +1,267 @@
+/*
+ * gscreen - A true color command wrapper for terminal programs
+ * Copyright (C) 2025 Gamunu Balagalla <gamunu@fastcode.io>
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program; if not, write to the Free Software Foundation, Inc.,
+ * 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+ */
+
+use std::io::{self, Write};
+use std::sync::Arc;
+use vte::Parser;
+
+#[cfg(feature = "async")]
+use std::pin::Pin;
+#[cfg(feature = "async")]
+use std::task::{ready, Context, Poll};
+#[cfg(feature = "async")]
+use tokio::io::AsyncWrite;
+
+use crate::color::{ColorDepth, ColorMetric, ColorTarget, Palette};
+use crate::modes::ChildModes;
+use crate::vte_handler::VteHandler;
+
+/// Settings for a `ColorConverter`: 256 colors and the fast metric unless
+/// changed, for a terminal whose palette isn't known
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ColorConverterBuilder {
+    target: ColorTarget,
+}
+
+impl ColorConverterBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of colors to convert to
+    pub fn depth(mut self, depth: ColorDepth) -> Self {
+        self.target.depth = depth;
+        self
+    }
+
+    /// How the nearest palette color is picked
+    pub fn metric(mut self, metric: ColorMetric) -> Self {
+        self.target.metric = metric;
+        self
+    }
+
+    /// Colors 0-15 of the terminal the output is meant for, which 256-color
+    /// conversion may then pick too
+    pub fn palette(mut self, palette: Palette) -> Self {
+        self.target.palette = Some(palette);
+        self
+    }
+
+    pub fn build<W: Write>(self, writer: W) -> ColorConverter<W> {
+        ColorConverter {
+            parser: Parser::new(),
+            handler: handler(writer, self.target),
+        }
+    }
+
+    #[cfg(feature = "async")]
+    pub fn build_async<W: AsyncWrite + Unpin>(self, writer: W) -> AsyncColorConverter<W> {
+        AsyncColorConverter {
+            inner: writer,
+            parser: Parser::new(),
+            handler: handler(Vec::new(), self.target),
+            written: 0,
+        }
+    }
+}
+
+/// A handler that only converts colors. Queries are passed on untouched,
+/// there is no program here to answer.
+fn handler<W: Write>(writer: W, target: ColorTarget) -> VteHandler<W> {
+    VteHandler::new(writer, true, target, Arc::new(ChildModes::default()))
+}
+
+/// Converts the colors in terminal output written to it and passes the
+/// result on to `W`. Everything else, including sequences split across
+/// writes, comes out as it went in.
+pub struct ColorConverter<W> {
+    parser: Parser,
+    handler: VteHandler<W>,
+}
+
+impl<W: Write> ColorConverter<W> {
+    pub fn get_ref(&self) -> &W {
+        self.handler.get_ref()
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        self.handler.get_mut()
+    }
+
+    /// The writer back, without flushing it. A sequence cut off by the end
+    /// of the input is dropped.
+    pub fn into_inner(self) -> W {
+        self.handler.into_inner()
+    }
+}
+
+impl<W: Write> Write for ColorConverter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.handler.process(&mut self.parser, buf);
+        match self.handler.take_write_error() {
+            Some(err) => Err(err),
+            None => Ok(buf.len()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.handler.flush()
+    }
+}
+
+/// `ColorConverter` for tokio writers. Each write is converted into a
+/// buffer that is passed on before the next write is accepted.
+#[cfg(feature = "async")]
+pub struct AsyncColorConverter<W> {
+    inner: W,
+    parser: Parser,
+    handler: VteHandler<Vec<u8>>,
+    /// How much of the converted buffer `inner` has taken
+    written: usize,
+}
+
+#[cfg(feature = "async")]
+impl<W: AsyncWrite + Unpin> AsyncColorConverter<W> {
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// The writer back, without flushing it or what was converted for it
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    /// Pass converted output on until none is left
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let converted = self.handler.get_mut();
+        while self.written < converted.len() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &converted[self.written..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.written += n;
+        }
+        converted.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(feature = "async")]
+impl<W: AsyncWrite + Unpin> AsyncWrite for AsyncColorConverter<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        this.handler.process(&mut this.parser, buf);
+        // Start passing it on; whatever is left waits for the next call
+        let _ = this.poll_drain(cx);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRUECOLOR: &[u8] = b"\x1b[1;38;2;255;0;0mred\x1b[m \x1b]0;title\x07";
+
+    #[test]
+    fn test_converter_writes_through() {
+        let mut converter = ColorConverterBuilder::new()
+            .depth(ColorDepth::Ansi16)
+            .build(Vec::new());
+        // Split in the middle of the SGR
+        converter.write_all(&TRUECOLOR[..10]).unwrap();
+        converter.write_all(&TRUECOLOR[10..]).unwrap();
+        converter.flush().unwrap();
+
+        assert_eq!(
+            converter.into_inner(),
+            b"\x1b[1;91mred\x1b[m \x1b]0;title\x07"
+        );
+    }
+
+    #[test]
+    fn test_palette_used_for_bright_colors() {
+        // A theme whose bright yellow is really a red
+        let mut palette = Palette::XTERM;
+        palette.colors[11] = palette.colors[1];
+        let mut converter = ColorConverterBuilder::new()
+            .depth(ColorDepth::Ansi8)
+            .palette(palette)
+            .build(Vec::new());
+        converter.write_all(b"\x1b[93;103m\x1b[38;5;11m").unwrap();
+
+        assert_eq!(converter.into_inner(), b"\x1b[31;41m\x1b[31m");
+    }
+
+    #[test]
+    fn test_write_errors_reported() {
+        struct Full;
+        impl Write for Full {
+            fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+                Err(io::ErrorKind::StorageFull.into())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut converter = ColorConverterBuilder::new().build(Full);
+        let err = converter.write(b"text").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn test_async_converter_writes_through() {
+        use tokio::io::AsyncWriteExt;
+
+        let mut converter = ColorConverterBuilder::new()
+            .depth(ColorDepth::Ansi256)
+            .build_async(Vec::new());
+        converter.write_all(TRUECOLOR).await.unwrap();
+        converter.flush().await.unwrap();
+
+        assert_eq!(
+            converter.into_inner(),
+            b"\x1b[1;38;5;196mred\x1b[m \x1b]0;title\x07"
+        );
+    }
+}
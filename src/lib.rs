@@ -0,0 +1,55 @@
+/*
+ * gscreen - A true color command wrapper for terminal programs
+ * Copyright (C) 2025 Gamunu Balagalla <gamunu@fastcode.io>
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program; if not, write to the Free Software Foundation, Inc.,
+ * 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+ */
+
+//! The color conversion behind gscreen, for use in other programs.
+//!
+//! `ColorConverter` rewrites the colors in terminal output for a terminal
+//! with fewer colors and passes everything else through:
+//!
+//! ```
+//! use gscreen::{ColorConverterBuilder, ColorDepth};
+//! use std::io::Write;
+//!
+//! let mut converter = ColorConverterBuilder::new()
+//!     .depth(ColorDepth::Ansi256)
+//!     .build(Vec::new());
+//! converter.write_all(b"\x1b[38;2;255;0;0mred\x1b[m").unwrap();
+//! assert_eq!(converter.into_inner(), b"\x1b[38;5;196mred\x1b[m");
+//! ```
+//!
+//! With the `async` feature, `build_async` gives a tokio `AsyncWrite`.
+
+pub mod color;
+mod converter;
+mod modes;
+pub mod screen;
+mod vte_handler;
+
+/// The parts of the output pipeline the gscreen binary and benchmarks use.
+/// Not part of the API, they change without notice.
+#[doc(hidden)]
+pub mod __private {
+    pub use crate::modes::ChildModes;
+    pub use crate::vte_handler::{InputVteHandler, VteHandler};
+}
+
+pub use color::{ColorDepth, ColorMetric, ColorTarget, Palette};
+#[cfg(feature = "async")]
+pub use converter::AsyncColorConverter;
+pub use converter::{ColorConverter, ColorConverterBuilder};
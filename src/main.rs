@@ -18,13 +18,30 @@
  */
 
 use anyhow::{Context, Result};
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
-mod color;
+mod async_file;
+mod config;
+mod convert;
+mod play;
+mod probe;
 mod proxy;
 mod pty;
+mod record;
+mod signals;
 mod terminal;
-mod vte_handler;
+
+use gscreen::__private::ChildModes;
+use gscreen::color::{ColorDepth, ColorMetric, ColorTarget};
+use play::{PlayOptions, Recording};
+use probe::ProbeMode;
+use proxy::{InputMode, ProxyOptions};
+use pty::ChildEnv;
+use record::{RecordStage, Recorder, Session};
+use signals::{ForwardedSignal, DEFAULT_FORWARDED_SIGNALS};
+use std::path::PathBuf;
+use std::sync::Arc;
+use terminal::TerminalGuard;
 
 #[derive(Parser)]
 #[command(
@@ -32,12 +49,19 @@ mod vte_handler;
     version = "0.2.0",
     about = "A true color command wrapper for terminal programs",
     author = "Gamunu Balagalla <gamunu@fastcode.io>",
-    trailing_var_arg = true
+    trailing_var_arg = true,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
 )]
 struct Args {
+    /// Something other than running a command; use `--` to run a command
+    /// with the same name
+    #[command(subcommand)]
+    mode: Option<Mode>,
+
     /// The command to run
-    #[arg(value_name = "COMMAND")]
-    command: String,
+    #[arg(value_name = "COMMAND", required_unless_present = "filter")]
+    command: Option<String>,
 
     /// Arguments to pass to the command
     #[arg(value_name = "ARGS", num_args = 0.., allow_hyphen_values = true)]
@@ -46,35 +70,412 @@ struct Args {
     /// Enable debug output
     #[arg(long, short, help = "Enable debug output")]
     debug: bool,
+
+    /// Convert colors from stdin to stdout instead of running a command,
+    /// like `gscreen convert`
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "command",
+            "term",
+            "env",
+            "unset_env",
+            "input_mode",
+            "forward_signals",
+            "probe",
+            "record",
+        ]
+    )]
+    filter: bool,
+
+    /// Config file to use [default: $XDG_CONFIG_HOME/gscreen/config.toml]
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Config profile to use [default: the one named after the command]
+    #[arg(long, value_name = "NAME")]
+    profile: Option<String>,
+
+    /// TERM for the command [default: xterm-256color]
+    #[arg(long, value_name = "TERM")]
+    term: Option<String>,
+
+    /// Set a variable in the command's environment, can be repeated
+    #[arg(long = "env", value_name = "KEY=VALUE", value_parser = pty::parse_env_assignment)]
+    env: Vec<(String, String)>,
+
+    /// Remove a variable from the command's environment, can be repeated
+    #[arg(long = "unset-env", value_name = "KEY")]
+    unset_env: Vec<String>,
+
+    /// Number of colors the outer terminal can display [default: detected]
+    #[arg(long, value_enum)]
+    color_depth: Option<ColorDepth>,
+
+    /// How keyboard input is forwarded to the command [default: raw]
+    #[arg(long, value_enum)]
+    input_mode: Option<InputMode>,
+
+    /// Metric used to pick the nearest palette color when downgrading
+    /// [default: fast]
+    #[arg(long, value_enum)]
+    color_metric: Option<ColorMetric>,
+
+    /// Signals passed on to the command's process group, e.g.
+    /// `--forward-signals=term,int`; without a value none are forwarded
+    /// [default: term,hup,quit,usr1,usr2]
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        num_args = 0..,
+        require_equals = true
+    )]
+    forward_signals: Option<Vec<ForwardedSignal>>,
+
+    /// Ask the terminal what it supports at startup; results are cached per
+    /// terminal unless `fresh` [default: auto]
+    #[arg(long, value_enum)]
+    probe: Option<ProbeMode>,
+
+    /// Record the session to an asciicast v2 file
+    #[arg(long, value_name = "FILE")]
+    record: Option<PathBuf>,
+
+    /// Output to record: as shown, or as the command wrote it
+    /// [default: converted]
+    #[arg(long, value_enum, requires = "record")]
+    record_stage: Option<RecordStage>,
+
+    /// Also record keyboard input, which may include passwords
+    #[arg(long, requires = "record")]
+    record_input: bool,
+}
+
+#[derive(Subcommand)]
+enum Mode {
+    /// Replay an asciicast v2 or ttyrec recording, converting its colors
+    /// for this terminal. Space pauses, `.` steps while paused, `q` quits.
+    Play(PlayArgs),
+    /// Convert the colors in a file or stream for the target color depth,
+    /// without running anything
+    Convert(ConvertArgs),
+}
+
+#[derive(clap::Args)]
+struct ConvertArgs {
+    /// File to convert [default: stdin]
+    #[arg(value_name = "INPUT")]
+    input: Option<PathBuf>,
+
+    /// File to write to [default: stdout]
+    #[arg(long, short, value_name = "FILE")]
+    output: Option<PathBuf>,
+
+    /// Enable debug output
+    #[arg(long, short)]
+    debug: bool,
+
+    /// Config file to use [default: $XDG_CONFIG_HOME/gscreen/config.toml]
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Config profile to use [default: convert]
+    #[arg(long, value_name = "NAME")]
+    profile: Option<String>,
+
+    /// Number of colors to convert to [default: detected]
+    #[arg(long, value_enum)]
+    color_depth: Option<ColorDepth>,
+
+    /// Metric used to pick the nearest palette color when downgrading
+    /// [default: fast]
+    #[arg(long, value_enum)]
+    color_metric: Option<ColorMetric>,
+}
+
+#[derive(clap::Args)]
+struct PlayArgs {
+    /// The recording to play
+    #[arg(value_name = "FILE")]
+    file: PathBuf,
+
+    /// Playback speed, 2 plays twice as fast
+    #[arg(long, short, default_value_t = 1.0, value_parser = play::parse_positive)]
+    speed: f64,
+
+    /// Cut pauses down to this many seconds [default: the recording's limit]
+    #[arg(long, short, value_name = "SECONDS", value_parser = play::parse_positive)]
+    idle_time_limit: Option<f64>,
+
+    /// Enable debug output
+    #[arg(long, short)]
+    debug: bool,
+
+    /// Config file to use, whose `play` profile applies on top
+    /// [default: $XDG_CONFIG_HOME/gscreen/config.toml]
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Number of colors the outer terminal can display [default: detected]
+    #[arg(long, value_enum)]
+    color_depth: Option<ColorDepth>,
+
+    /// Metric used to pick the nearest palette color when downgrading
+    /// [default: fast]
+    #[arg(long, value_enum)]
+    color_metric: Option<ColorMetric>,
+
+    /// Ask the terminal what it supports [default: auto]
+    #[arg(long, value_enum)]
+    probe: Option<ProbeMode>,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
+    match args.mode {
+        Some(Mode::Play(play_args)) => return play_recording(play_args).await,
+        Some(Mode::Convert(convert_args)) => return convert_stream(convert_args).await,
+        None if args.filter => {
+            return convert_stream(ConvertArgs {
+                input: None,
+                output: None,
+                debug: args.debug,
+                config: args.config,
+                profile: args.profile,
+                color_depth: args.color_depth,
+                color_metric: args.color_metric,
+            })
+            .await
+        }
+        None => {}
+    }
+    let command = args.command.clone().context("No command given")?;
 
     // Validate that the command exists
-    if which::which(&args.command).is_err() {
-        anyhow::bail!("Command '{}' not found in PATH", args.command);
+    if which::which(&command).is_err() {
+        anyhow::bail!("Command '{}' not found in PATH", command);
     }
 
     if args.debug {
-        println!("Starting {} with true color support...", args.command);
+        println!("Starting {} with true color support...", command);
     }
 
-    // Set up terminal for true color support and get capabilities
-    let has_osc_support = terminal::setup_true_color_environment(args.debug)?;
+    // Settings from the config file, with the command's profile applied;
+    // the command line wins over both
+    let (config, config_path) = config::load(args.config.as_deref())?;
+    let settings = config.for_command(args.profile.as_deref(), &command)?;
+    if args.debug {
+        match &config_path {
+            Some(path) => eprintln!("Config: {}", path.display()),
+            None => eprintln!("Config: none, using defaults"),
+        }
+    }
+
+    // Pass termination and user signals on instead of dying with the child
+    // still running and the terminal left in raw mode. They are caught from
+    // here on and go to the child once it is running.
+    let forward_signals = args
+        .forward_signals
+        .or(settings.forward_signals)
+        .unwrap_or_else(|| DEFAULT_FORWARDED_SIGNALS.to_vec());
+    let signal_forwarder = signals::forward_signals(&forward_signals)?;
+
+    // Modes the child turns on in the outer terminal, which the guard turns
+    // off again however gscreen exits, probing included
+    let modes = Arc::new(ChildModes::default());
+    let guard = TerminalGuard::new(Arc::clone(&modes));
+
+    // Find out what the outer terminal can do
+    let probe_mode = args.probe.or(settings.probe).unwrap_or_default();
+    let (capabilities, typeahead) = terminal::detect_capabilities(probe_mode, args.debug).await;
+
+    // The command's environment, where --env and --unset-env win over the
+    // config file just like the other options
+    let mut child_env = ChildEnv {
+        term: args.term.clone().or(settings.term.clone()),
+        vars: settings.env.clone(),
+        unset: settings.unset_env.clone(),
+    };
+    for (key, value) in &args.env {
+        child_env.unset.retain(|unset| unset != key);
+        child_env.vars.insert(key.clone(), value.clone());
+    }
+    child_env.unset.extend(args.unset_env.iter().cloned());
 
     // Spawn the command in a PTY
-    let mut pty_pair =
-        pty::create_pty_with_command(&args.command, &args.args).context("Failed to create PTY")?;
+    let mut pty = pty::create_pty_with_command(&command, &args.args, &child_env)
+        .context("Failed to create PTY")?;
+    if let Some(pid) = pty.child.process_id() {
+        signal_forwarder.set_child(pid);
+    }
+
+    let mut recorder = match &args.record {
+        Some(path) => {
+            let command_line = std::iter::once(&command)
+                .chain(&args.args)
+                .map(String::as_str)
+                .collect::<Vec<_>>()
+                .join(" ");
+            let session = Session {
+                size: pty
+                    .master
+                    .get_size()
+                    .unwrap_or_else(|_| pty::terminal_size()),
+                term: child_env.term(),
+                command: &command_line,
+            };
+            let stage = args.record_stage.unwrap_or_default();
+            Some(Recorder::create(path, &session, stage, args.record_input)?)
+        }
+        None => None,
+    };
+
+    // An explicit --color-depth or configured one wins over what was detected
+    let color_target = ColorTarget {
+        depth: args
+            .color_depth
+            .or(settings.color_depth)
+            .unwrap_or(capabilities.color_depth),
+        metric: args
+            .color_metric
+            .or(settings.color_metric)
+            .unwrap_or_default(),
+        palette: settings.colors.palette,
+    };
+
+    if args.debug {
+        let source = if args.color_depth.is_some() {
+            "from --color-depth"
+        } else if settings.color_depth.is_some() {
+            "from config"
+        } else {
+            "detected"
+        };
+        if color_target.depth == ColorDepth::TrueColor {
+            eprintln!(
+                "Color depth: truecolor ({}), passing RGB colors through",
+                source
+            );
+        } else {
+            eprintln!(
+                "Color depth: {} ({}), converting colors with the {} metric",
+                color_target.depth, source, color_target.metric
+            );
+        }
+    }
 
     // Start bidirectional I/O proxy with capability info
-    let result = proxy::run_proxy(&mut pty_pair, has_osc_support)
+    let options = ProxyOptions {
+        has_osc_support: capabilities.has_osc_support,
+        color_target,
+        input_mode: args.input_mode.or(settings.input_mode).unwrap_or_default(),
+        default_colors: settings
+            .colors
+            .default_colors(terminal::detect_default_colors(&capabilities)),
+        sgr_mouse: capabilities.sgr_mouse,
+        typeahead,
+    };
+    let result = proxy::run_proxy(&mut pty, options, modes, recorder.as_mut())
         .await
         .context("I/O proxy failed");
 
-    // Clean up terminal
-    terminal::restore_terminal()?;
+    // Clean up terminal before anything that could block, process::exit
+    // would skip the guard's destructor
+    drop(guard);
 
+    // Collect the child's exit status once the proxy is done. If the proxy
+    // failed the child may still be running and is hung up on instead.
+    let exit_code = match &result {
+        Ok(()) => pty::wait_for_exit_code(pty.child.as_mut()),
+        Err(_) => pty::hang_up_child(pty.child.as_mut()),
+    };
+
+    // A session that ended in an error is still worth having on record
+    let recorded = match (recorder, &args.record) {
+        (Some(recorder), Some(path)) => recorder
+            .finish()
+            .map(drop)
+            .with_context(|| format!("Failed to write recording {}", path.display())),
+        _ => Ok(()),
+    };
+
+    result?;
+    recorded?;
+    std::process::exit(exit_code?);
+}
+
+/// `gscreen play`: show a recording through the same color conversion as a
+/// live session
+async fn play_recording(args: PlayArgs) -> Result<()> {
+    let (config, _) = config::load(args.config.as_deref())?;
+    let settings = config.for_command(None, "play")?;
+    let recording = Recording::load(&args.file)?;
+
+    // Recordings switch modes like the programs in them, undo them after
+    let modes = Arc::new(ChildModes::default());
+    let guard = TerminalGuard::new(Arc::clone(&modes));
+
+    let probe_mode = args.probe.or(settings.probe).unwrap_or_default();
+    // Keys pressed while probing are dropped, they would only pause or quit
+    let (capabilities, _) = terminal::detect_capabilities(probe_mode, args.debug).await;
+    let color_target = ColorTarget {
+        depth: args
+            .color_depth
+            .or(settings.color_depth)
+            .unwrap_or(capabilities.color_depth),
+        metric: args
+            .color_metric
+            .or(settings.color_metric)
+            .unwrap_or_default(),
+        palette: settings.colors.palette,
+    };
+
+    if args.debug {
+        if let Some((width, height)) = recording.size {
+            eprintln!("Recorded at {}x{}", width, height);
+        }
+        eprintln!(
+            "Playing {} frames, color depth: {}",
+            recording.frames.len(),
+            color_target.depth
+        );
+    }
+
+    let options = PlayOptions {
+        speed: args.speed,
+        idle_time_limit: args.idle_time_limit,
+        color_target,
+    };
+    let result = play::play(&recording, options, modes).await;
+
+    drop(guard);
     result
 }
+
+/// `gscreen convert` and `--filter`: the output pipeline without a PTY,
+/// leaving the terminal alone
+async fn convert_stream(args: ConvertArgs) -> Result<()> {
+    let (config, _) = config::load(args.config.as_deref())?;
+    let settings = config.for_command(args.profile.as_deref(), "convert")?;
+
+    // Nothing is probed, the terminal may not even be involved
+    let (capabilities, _) = terminal::detect_capabilities(ProbeMode::Off, args.debug).await;
+    let color_target = ColorTarget {
+        depth: args
+            .color_depth
+            .or(settings.color_depth)
+            .unwrap_or(capabilities.color_depth),
+        metric: args
+            .color_metric
+            .or(settings.color_metric)
+            .unwrap_or_default(),
+        palette: settings.colors.palette,
+    };
+    if args.debug {
+        eprintln!("Converting to color depth {}", color_target.depth);
+    }
+
+    convert::convert_files(args.input.as_deref(), args.output.as_deref(), color_target)
+}
@@ -0,0 +1,454 @@
+/*
+ * gscreen - A true color command wrapper for terminal programs
+ * Copyright (C) 2025 Gamunu Balagalla <gamunu@fastcode.io>
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program; if not, write to the Free Software Foundation, Inc.,
+ * 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+ */
+
+#[cfg(feature = "cli")]
+use crossterm::event::{KeyModifiers, MouseButton, MouseEvent, MouseEventKind};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+/// DECSET 1: application cursor keys
+const APP_CURSOR_KEYS: u16 = 1;
+/// DECSET 25: cursor visible
+const CURSOR_VISIBLE: u16 = 25;
+/// DECSET 47, 1047 and 1049 all switch to the alternate screen
+const ALT_SCREEN: [u16; 3] = [47, 1047, 1049];
+/// DECSET 2004: bracketed paste
+const BRACKETED_PASTE: u16 = 2004;
+
+/// Turns off every mouse mode and encoding
+const MOUSE_RESET: &[u8] = b"\x1b[?1000;1002;1003;1005;1006;1015l";
+/// Leaves the alternate screen, restoring the cursor saved on entry
+const ALT_SCREEN_RESET: &[u8] = b"\x1b[?1049l";
+const CURSOR_SHOW: &[u8] = b"\x1b[?25h";
+const APP_CURSOR_KEYS_RESET: &[u8] = b"\x1b[?1l";
+/// DECKPNM: numeric keypad
+const KEYPAD_RESET: &[u8] = b"\x1b>";
+const BRACKETED_PASTE_RESET: &[u8] = b"\x1b[?2004l";
+
+/// Marks the start of pasted text in bracketed paste mode
+pub const PASTE_START: &[u8] = b"\x1b[200~";
+/// Marks the end of pasted text in bracketed paste mode
+pub const PASTE_END: &[u8] = b"\x1b[201~";
+
+// Mouse reports are encoded from crossterm events, which only the binary has
+/// Button code reported for a release outside SGR encoding
+#[cfg(feature = "cli")]
+const RELEASE_BUTTON: u8 = 3;
+/// Added to the button code for drags and motion
+#[cfg(feature = "cli")]
+const MOTION_BIT: u8 = 32;
+/// Added to the button code for wheel events
+#[cfg(feature = "cli")]
+const WHEEL_BASE: u8 = 64;
+/// Offset added to every value in the X10 and UTF-8 encodings
+#[cfg(feature = "cli")]
+const X10_OFFSET: u32 = 32;
+/// Largest value the UTF-8 encoding can carry in two bytes
+#[cfg(feature = "cli")]
+const UTF8_LIMIT: u32 = 0x7ff;
+
+/// Which mouse events the child asked to receive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MouseTracking {
+    Off,
+    /// 1000: presses, releases and the wheel
+    Normal,
+    /// 1002: also motion while a button is held
+    ButtonEvent,
+    /// 1003: all motion
+    AnyEvent,
+}
+
+/// How the child wants mouse reports encoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MouseEncoding {
+    /// `CSI M` followed by three bytes
+    X10,
+    /// 1005: like X10, with values sent as UTF-8 characters
+    Utf8,
+    /// 1006: `CSI < b ; x ; y M` or `m`
+    Sgr,
+    /// 1015: `CSI b ; x ; y M`
+    Urxvt,
+}
+
+impl MouseTracking {
+    fn from_mode(mode: u16) -> Option<Self> {
+        match mode {
+            1000 => Some(MouseTracking::Normal),
+            1002 => Some(MouseTracking::ButtonEvent),
+            1003 => Some(MouseTracking::AnyEvent),
+            _ => None,
+        }
+    }
+
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => MouseTracking::Normal,
+            2 => MouseTracking::ButtonEvent,
+            3 => MouseTracking::AnyEvent,
+            _ => MouseTracking::Off,
+        }
+    }
+}
+
+impl MouseEncoding {
+    fn from_mode(mode: u16) -> Option<Self> {
+        match mode {
+            1005 => Some(MouseEncoding::Utf8),
+            1006 => Some(MouseEncoding::Sgr),
+            1015 => Some(MouseEncoding::Urxvt),
+            _ => None,
+        }
+    }
+
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => MouseEncoding::Utf8,
+            2 => MouseEncoding::Sgr,
+            3 => MouseEncoding::Urxvt,
+            _ => MouseEncoding::X10,
+        }
+    }
+}
+
+/// Whether `mode` selects a mouse report encoding rather than what to report
+pub fn is_mouse_encoding(mode: u16) -> bool {
+    MouseEncoding::from_mode(mode).is_some()
+}
+
+/// Terminal modes the child has turned on, tracked from its output so the
+/// input side can encode events the way the child expects
+#[derive(Debug)]
+pub struct ChildModes {
+    bracketed_paste: AtomicBool,
+    mouse_tracking: AtomicU8,
+    mouse_encoding: AtomicU8,
+    alt_screen: AtomicBool,
+    cursor_hidden: AtomicBool,
+    app_cursor_keys: AtomicBool,
+    app_keypad: AtomicBool,
+}
+
+impl Default for ChildModes {
+    fn default() -> Self {
+        Self {
+            bracketed_paste: AtomicBool::new(false),
+            mouse_tracking: AtomicU8::new(MouseTracking::Off as u8),
+            mouse_encoding: AtomicU8::new(MouseEncoding::X10 as u8),
+            alt_screen: AtomicBool::new(false),
+            cursor_hidden: AtomicBool::new(false),
+            app_cursor_keys: AtomicBool::new(false),
+            app_keypad: AtomicBool::new(false),
+        }
+    }
+}
+
+impl ChildModes {
+    /// Record a DECSET (`CSI ? N h`) or DECRST (`CSI ? N l`) from the child.
+    /// Like xterm, the last tracking mode and encoding set win, and resetting
+    /// any of them turns that setting off.
+    pub fn set_private_mode(&self, mode: u16, enabled: bool) {
+        if mode == BRACKETED_PASTE {
+            self.bracketed_paste.store(enabled, Ordering::Relaxed);
+        } else if mode == APP_CURSOR_KEYS {
+            self.app_cursor_keys.store(enabled, Ordering::Relaxed);
+        } else if mode == CURSOR_VISIBLE {
+            self.cursor_hidden.store(!enabled, Ordering::Relaxed);
+        } else if ALT_SCREEN.contains(&mode) {
+            self.alt_screen.store(enabled, Ordering::Relaxed);
+        } else if let Some(tracking) = MouseTracking::from_mode(mode) {
+            let tracking = if enabled {
+                tracking
+            } else {
+                MouseTracking::Off
+            };
+            self.mouse_tracking.store(tracking as u8, Ordering::Relaxed);
+        } else if let Some(encoding) = MouseEncoding::from_mode(mode) {
+            let encoding = if enabled {
+                encoding
+            } else {
+                MouseEncoding::X10
+            };
+            self.mouse_encoding.store(encoding as u8, Ordering::Relaxed);
+        }
+    }
+
+    /// Record DECKPAM (`ESC =`) or DECKPNM (`ESC >`) from the child
+    pub fn set_keypad_application(&self, enabled: bool) {
+        self.app_keypad.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Sequences undoing every mode the child left on, to hand the outer
+    /// terminal back in the state we found it
+    pub fn reset_sequence(&self) -> Vec<u8> {
+        let mut reset = Vec::new();
+        if self.mouse_tracking() != MouseTracking::Off
+            || self.mouse_encoding() != MouseEncoding::X10
+        {
+            reset.extend_from_slice(MOUSE_RESET);
+        }
+
+        let flags = [
+            (&self.alt_screen, ALT_SCREEN_RESET),
+            (&self.cursor_hidden, CURSOR_SHOW),
+            (&self.app_cursor_keys, APP_CURSOR_KEYS_RESET),
+            (&self.app_keypad, KEYPAD_RESET),
+            (&self.bracketed_paste, BRACKETED_PASTE_RESET),
+        ];
+        for (flag, sequence) in flags {
+            if flag.load(Ordering::Relaxed) {
+                reset.extend_from_slice(sequence);
+            }
+        }
+        reset
+    }
+
+    pub fn bracketed_paste(&self) -> bool {
+        self.bracketed_paste.load(Ordering::Relaxed)
+    }
+
+    pub fn mouse_tracking(&self) -> MouseTracking {
+        MouseTracking::from_u8(self.mouse_tracking.load(Ordering::Relaxed))
+    }
+
+    pub fn mouse_encoding(&self) -> MouseEncoding {
+        MouseEncoding::from_u8(self.mouse_encoding.load(Ordering::Relaxed))
+    }
+
+    /// Encode pasted text for the child, bracketing it if the child asked
+    pub fn encode_paste(&self, text: &str) -> Vec<u8> {
+        if !self.bracketed_paste() {
+            return text.as_bytes().to_vec();
+        }
+
+        let mut bytes = Vec::with_capacity(PASTE_START.len() + text.len() + PASTE_END.len());
+        bytes.extend_from_slice(PASTE_START);
+        bytes.extend_from_slice(text.as_bytes());
+        bytes.extend_from_slice(PASTE_END);
+        bytes
+    }
+
+    /// Encode a mouse event as the child asked for it, or `None` if the
+    /// child didn't ask for this kind of event or it can't be encoded
+    #[cfg(feature = "cli")]
+    pub fn encode_mouse(&self, event: &MouseEvent) -> Option<Vec<u8>> {
+        let tracking = self.mouse_tracking();
+        let (button, release) = match event.kind {
+            _ if tracking == MouseTracking::Off => return None,
+            MouseEventKind::Down(button) => (button_code(button), false),
+            MouseEventKind::Up(button) => (button_code(button), true),
+            MouseEventKind::Drag(button) if tracking != MouseTracking::Normal => {
+                (button_code(button) + MOTION_BIT, false)
+            }
+            MouseEventKind::Moved if tracking == MouseTracking::AnyEvent => {
+                (RELEASE_BUTTON + MOTION_BIT, false)
+            }
+            MouseEventKind::ScrollUp => (WHEEL_BASE, false),
+            MouseEventKind::ScrollDown => (WHEEL_BASE + 1, false),
+            MouseEventKind::ScrollLeft => (WHEEL_BASE + 2, false),
+            MouseEventKind::ScrollRight => (WHEEL_BASE + 3, false),
+            _ => return None,
+        };
+        let button = button | modifier_bits(event.modifiers);
+        let x = u32::from(event.column) + 1;
+        let y = u32::from(event.row) + 1;
+
+        match self.mouse_encoding() {
+            MouseEncoding::Sgr => {
+                let end = if release { 'm' } else { 'M' };
+                Some(format!("\x1b[<{};{};{}{}", button, x, y, end).into_bytes())
+            }
+            MouseEncoding::Urxvt => {
+                let button = u32::from(release_code(button, release)) + X10_OFFSET;
+                Some(format!("\x1b[{};{};{}M", button, x, y).into_bytes())
+            }
+            MouseEncoding::X10 => {
+                let values = [u32::from(release_code(button, release)), x, y];
+                let mut bytes = b"\x1b[M".to_vec();
+                for value in values {
+                    bytes.push(u8::try_from(value + X10_OFFSET).ok()?);
+                }
+                Some(bytes)
+            }
+            MouseEncoding::Utf8 => {
+                let values = [u32::from(release_code(button, release)), x, y];
+                let mut text = String::from("\x1b[M");
+                for value in values {
+                    let value = value + X10_OFFSET;
+                    if value > UTF8_LIMIT {
+                        return None;
+                    }
+                    text.push(char::from_u32(value)?);
+                }
+                Some(text.into_bytes())
+            }
+        }
+    }
+}
+
+#[cfg(feature = "cli")]
+fn button_code(button: MouseButton) -> u8 {
+    match button {
+        MouseButton::Left => 0,
+        MouseButton::Middle => 1,
+        MouseButton::Right => 2,
+    }
+}
+
+#[cfg(feature = "cli")]
+fn modifier_bits(modifiers: KeyModifiers) -> u8 {
+    let mut bits = 0;
+    if modifiers.contains(KeyModifiers::SHIFT) {
+        bits |= 4;
+    }
+    if modifiers.contains(KeyModifiers::ALT) {
+        bits |= 8;
+    }
+    if modifiers.contains(KeyModifiers::CONTROL) {
+        bits |= 16;
+    }
+    bits
+}
+
+/// Outside SGR encoding a release doesn't say which button was let go
+#[cfg(feature = "cli")]
+fn release_code(button: u8, release: bool) -> u8 {
+    if release {
+        (button & !0b11) | RELEASE_BUTTON
+    } else {
+        button
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_paste_bracketed_only_when_enabled() {
+        let modes = ChildModes::default();
+        assert_eq!(modes.encode_paste("ls\r"), b"ls\r");
+
+        modes.set_private_mode(2004, true);
+        assert_eq!(modes.encode_paste("ls\r"), b"\x1b[200~ls\r\x1b[201~");
+
+        modes.set_private_mode(2004, false);
+        assert!(!modes.bracketed_paste());
+    }
+
+    #[test]
+    fn test_reset_sequence_undoes_modes_left_on() {
+        let modes = ChildModes::default();
+        assert_eq!(modes.reset_sequence(), b"");
+
+        for mode in [1049, 25, 1, 1002, 1006, 2004] {
+            modes.set_private_mode(mode, true);
+        }
+        modes.set_private_mode(25, false);
+        modes.set_keypad_application(true);
+        assert_eq!(
+            String::from_utf8(modes.reset_sequence()).unwrap(),
+            "\x1b[?1000;1002;1003;1005;1006;1015l\x1b[?1049l\x1b[?25h\x1b[?1l\x1b>\x1b[?2004l"
+        );
+
+        // Modes the child turned off again are left alone
+        for mode in [1049, 1, 1002, 1006, 2004] {
+            modes.set_private_mode(mode, false);
+        }
+        modes.set_private_mode(25, true);
+        modes.set_keypad_application(false);
+        assert_eq!(modes.reset_sequence(), b"");
+    }
+
+    #[cfg(feature = "cli")]
+    type MouseCase<'a> = (&'a [u16], &'a MouseEvent, Option<&'a [u8]>);
+
+    #[cfg(feature = "cli")]
+    fn mouse(kind: MouseEventKind, modifiers: KeyModifiers) -> MouseEvent {
+        MouseEvent {
+            kind,
+            column: 9,
+            row: 4,
+            modifiers,
+        }
+    }
+
+    #[cfg(feature = "cli")]
+    #[test]
+    fn test_mouse_events_encoded_as_requested() {
+        let none = KeyModifiers::NONE;
+        let left_down = mouse(MouseEventKind::Down(MouseButton::Left), none);
+        let left_up = mouse(MouseEventKind::Up(MouseButton::Left), none);
+        let drag = mouse(MouseEventKind::Drag(MouseButton::Left), none);
+        let moved = mouse(MouseEventKind::Moved, none);
+        let ctrl_wheel = mouse(MouseEventKind::ScrollDown, KeyModifiers::CONTROL);
+
+        let cases: [MouseCase; 12] = [
+            // Nothing is reported until the child enables tracking
+            (&[], &left_down, None),
+            (&[1000], &left_down, Some(b"\x1b[M *%")),
+            (&[1000], &left_up, Some(b"\x1b[M#*%")),
+            (&[1000], &drag, None),
+            (&[1002], &drag, Some(b"\x1b[M@*%")),
+            (&[1002], &moved, None),
+            (&[1003], &moved, Some(b"\x1b[MC*%")),
+            (&[1000, 1006], &left_up, Some(b"\x1b[<0;10;5m")),
+            (&[1000, 1006], &ctrl_wheel, Some(b"\x1b[<81;10;5M")),
+            (&[1000, 1015], &left_up, Some(b"\x1b[35;10;5M")),
+            (&[1000, 1005], &left_down, Some(b"\x1b[M *%")),
+            (&[1000, 1006, 1000], &left_down, Some(b"\x1b[<0;10;5M")),
+        ];
+
+        for (enabled, event, expected) in cases {
+            let modes = ChildModes::default();
+            for &mode in enabled {
+                modes.set_private_mode(mode, true);
+            }
+            assert_eq!(
+                modes.encode_mouse(event).as_deref(),
+                expected,
+                "modes {:?}, event {:?}",
+                enabled,
+                event
+            );
+        }
+    }
+
+    #[cfg(feature = "cli")]
+    #[test]
+    fn test_mouse_coordinates_beyond_x10_range() {
+        let modes = ChildModes::default();
+        modes.set_private_mode(1000, true);
+        let far = MouseEvent {
+            kind: MouseEventKind::Down(MouseButton::Left),
+            column: 299,
+            row: 0,
+            modifiers: KeyModifiers::NONE,
+        };
+        assert_eq!(modes.encode_mouse(&far), None);
+
+        modes.set_private_mode(1005, true);
+        assert_eq!(modes.encode_mouse(&far), Some("\x1b[M \u{14c}!".into()));
+
+        modes.set_private_mode(1000, false);
+        assert_eq!(modes.encode_mouse(&far), None);
+    }
+}
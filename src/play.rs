@@ -0,0 +1,421 @@
+/*
+ * gscreen - A true color command wrapper for terminal programs
+ * Copyright (C) 2025 Gamunu Balagalla <gamunu@fastcode.io>
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program; if not, write to the Free Software Foundation, Inc.,
+ * 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+ */
+
+use anyhow::{bail, Context, Result};
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use serde::Deserialize;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::{sleep_until, Instant};
+use vte::Parser;
+
+use gscreen::__private::{ChildModes, VteHandler};
+use gscreen::color::ColorTarget;
+
+/// Size of the stdout buffer, large enough for most frames
+const OUTPUT_BUFFER_SIZE: usize = 64 * 1024;
+/// Size of a ttyrec frame header: seconds, microseconds and length
+const TTYREC_HEADER_SIZE: usize = 12;
+
+/// Output recorded at some point of a session
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    /// Seconds since the start of the recording
+    pub time: f64,
+    pub data: Vec<u8>,
+}
+
+/// A recorded session, from asciicast v2 or ttyrec
+#[derive(Debug, Default)]
+pub struct Recording {
+    /// The terminal size it was recorded at, if the format says
+    pub size: Option<(u16, u16)>,
+    /// Longest pause the recording asks to be kept to
+    pub idle_time_limit: Option<f64>,
+    pub frames: Vec<Frame>,
+}
+
+#[derive(Deserialize)]
+struct AsciicastHeader {
+    version: u32,
+    width: u16,
+    height: u16,
+    idle_time_limit: Option<f64>,
+}
+
+impl Recording {
+    /// Read an asciicast v2 or ttyrec file, told apart by the JSON header
+    pub fn load(path: &Path) -> Result<Self> {
+        let bytes = std::fs::read(path)
+            .with_context(|| format!("Failed to read recording {}", path.display()))?;
+        let recording = if bytes.starts_with(b"{") {
+            Recording::from_asciicast(&String::from_utf8_lossy(&bytes))
+        } else {
+            Recording::from_ttyrec(&bytes)
+        };
+        recording.with_context(|| format!("Invalid recording {}", path.display()))
+    }
+
+    fn from_asciicast(text: &str) -> Result<Self> {
+        let mut lines = text.lines().enumerate();
+        let header: AsciicastHeader = match lines.next() {
+            Some((_, line)) => serde_json::from_str(line).context("line 1: invalid header")?,
+            None => bail!("empty file"),
+        };
+        if header.version != 2 {
+            bail!("asciicast version {} isn't supported", header.version);
+        }
+
+        let mut frames = Vec::new();
+        for (index, line) in lines {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let (time, code, data): (f64, String, String) = serde_json::from_str(line)
+                .with_context(|| format!("line {}: invalid event", index + 1))?;
+            // Input and resize events don't change what is shown
+            if code == "o" {
+                frames.push(Frame {
+                    time,
+                    data: data.into_bytes(),
+                });
+            }
+        }
+
+        Ok(Recording {
+            size: Some((header.width, header.height)),
+            idle_time_limit: header.idle_time_limit,
+            frames,
+        })
+    }
+
+    /// ttyrec frames are a little endian header of seconds, microseconds and
+    /// length followed by the data. A cut off last frame is dropped, that is
+    /// how recordings of crashed sessions end.
+    fn from_ttyrec(mut bytes: &[u8]) -> Result<Self> {
+        let mut frames = Vec::new();
+        let mut start = None;
+
+        while bytes.len() >= TTYREC_HEADER_SIZE {
+            let field = |index: usize| {
+                let at = index * 4;
+                u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+            };
+            let (seconds, micros, len) = (field(0), field(1), field(2) as usize);
+            if micros >= 1_000_000 {
+                bail!("not an asciicast v2 or ttyrec recording");
+            }
+            let Some(data) = bytes.get(TTYREC_HEADER_SIZE..TTYREC_HEADER_SIZE + len) else {
+                break;
+            };
+
+            let timestamp = seconds as f64 + micros as f64 / 1e6;
+            let start = *start.get_or_insert(timestamp);
+            frames.push(Frame {
+                time: timestamp - start,
+                data: data.to_vec(),
+            });
+            bytes = &bytes[TTYREC_HEADER_SIZE + len..];
+        }
+
+        Ok(Recording {
+            frames,
+            ..Recording::default()
+        })
+    }
+
+    /// How long to wait before each frame at `speed`, with pauses cut down
+    /// to `idle_time_limit`
+    fn delays(&self, speed: f64, idle_time_limit: Option<f64>) -> Vec<Duration> {
+        let mut previous = 0.0;
+        self.frames
+            .iter()
+            .map(|frame| {
+                let mut gap = (frame.time - previous).max(0.0);
+                previous = frame.time;
+                if let Some(limit) = idle_time_limit {
+                    gap = gap.min(limit);
+                }
+                Duration::from_secs_f64(gap / speed)
+            })
+            .collect()
+    }
+}
+
+/// Parse a speed or time limit as given on the command line, which has to
+/// be a positive number
+pub fn parse_positive(text: &str) -> Result<f64, String> {
+    match text.parse::<f64>() {
+        Ok(value) if value > 0.0 && value.is_finite() => Ok(value),
+        _ => Err(format!("expected a positive number, got '{}'", text)),
+    }
+}
+
+/// How to play a recording back
+#[derive(Debug, Clone, Copy)]
+pub struct PlayOptions {
+    pub speed: f64,
+    /// Longest pause to keep, overriding the recording's own limit
+    pub idle_time_limit: Option<f64>,
+    pub color_target: ColorTarget,
+}
+
+/// Keys understood during playback
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PlayerKey {
+    /// Space pauses and resumes
+    Pause,
+    /// `.` shows the next frame while paused
+    Step,
+    /// `q` or Ctrl-C stops playback
+    Quit,
+}
+
+/// Play `recording` to stdout with its original timing, converting colors
+/// to `color_target` like a live session would
+pub async fn play(
+    recording: &Recording,
+    options: PlayOptions,
+    modes: Arc<ChildModes>,
+) -> Result<()> {
+    let idle_time_limit = options.idle_time_limit.or(recording.idle_time_limit);
+    let delays = recording.delays(options.speed, idle_time_limit);
+
+    // Keys are only read from a terminal
+    let keys = if crossterm::tty::IsTty::is_tty(&std::io::stdin()) {
+        crossterm::terminal::enable_raw_mode().context("Failed to enable raw mode")?;
+        Some(spawn_key_reader())
+    } else {
+        None
+    };
+
+    // Queries in the recording are answered here and the answers dropped,
+    // the recorded program isn't around to read them
+    let stdout = BufWriter::with_capacity(OUTPUT_BUFFER_SIZE, std::io::stdout());
+    let mut vte_handler = VteHandler::new(stdout, false, options.color_target, modes);
+    play_frames(&recording.frames, &delays, keys, &mut vte_handler).await
+}
+
+/// Show `frames` through `vte_handler`, each after its delay, taking
+/// player keys from `keys` until they end
+async fn play_frames<W: Write>(
+    frames: &[Frame],
+    delays: &[Duration],
+    mut keys: Option<mpsc::Receiver<PlayerKey>>,
+    vte_handler: &mut VteHandler<W>,
+) -> Result<()> {
+    let mut parser = Parser::new();
+    let mut index = 0;
+    let mut paused = false;
+    let mut shown_at = Instant::now();
+
+    while let Some(frame) = frames.get(index) {
+        let due = shown_at + delays[index];
+        let step = tokio::select! {
+            _ = sleep_until(due), if !paused => {
+                shown_at = due;
+                true
+            }
+            key = next_key(&mut keys) => match key {
+                Some(PlayerKey::Pause) => {
+                    paused = !paused;
+                    // The frame's delay starts over on resume
+                    shown_at = Instant::now();
+                    false
+                }
+                Some(PlayerKey::Step) if paused => {
+                    shown_at = Instant::now();
+                    true
+                }
+                Some(PlayerKey::Step) => false,
+                Some(PlayerKey::Quit) => break,
+                None => {
+                    // Nothing can resume playback any more
+                    keys = None;
+                    paused = false;
+                    false
+                }
+            },
+        };
+
+        if step {
+            vte_handler.process(&mut parser, &frame.data);
+            vte_handler.take_replies();
+            vte_handler.flush().context("Failed to write to stdout")?;
+            index += 1;
+        }
+    }
+
+    Ok(())
+}
+
+/// The next key, or never if keys aren't read
+async fn next_key(keys: &mut Option<mpsc::Receiver<PlayerKey>>) -> Option<PlayerKey> {
+    match keys {
+        Some(receiver) => receiver.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Read keys on a thread, crossterm's reader blocks until one arrives
+fn spawn_key_reader() -> mpsc::Receiver<PlayerKey> {
+    let (sender, receiver) = mpsc::channel(1);
+    thread::spawn(move || {
+        while let Ok(event) = event::read() {
+            let Event::Key(KeyEvent {
+                code,
+                modifiers,
+                kind: KeyEventKind::Press,
+                ..
+            }) = event
+            else {
+                continue;
+            };
+            let key = match code {
+                KeyCode::Char(' ') => PlayerKey::Pause,
+                KeyCode::Char('.') => PlayerKey::Step,
+                KeyCode::Char('q') => PlayerKey::Quit,
+                KeyCode::Char('c') if modifiers.contains(KeyModifiers::CONTROL) => PlayerKey::Quit,
+                _ => continue,
+            };
+            if sender.blocking_send(key).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_asciicast_output_events_loaded() {
+        let recording = Recording::from_asciicast(
+            "{\"version\": 2, \"width\": 100, \"height\": 30, \"idle_time_limit\": 2.5}\n\
+             [0.5, \"o\", \"\\u001b[38;2;255;0;0mred\"]\n\
+             [0.7, \"i\", \"q\"]\n\
+             [0.9, \"r\", \"80x24\"]\n\
+             [1.25, \"o\", \"done\\r\\n\"]\n",
+        )
+        .unwrap();
+
+        assert_eq!(recording.size, Some((100, 30)));
+        assert_eq!(recording.idle_time_limit, Some(2.5));
+        let frames: Vec<(f64, &[u8])> = recording
+            .frames
+            .iter()
+            .map(|frame| (frame.time, &frame.data[..]))
+            .collect();
+        assert_eq!(
+            frames,
+            [
+                (0.5, &b"\x1b[38;2;255;0;0mred"[..]),
+                (1.25, &b"done\r\n"[..])
+            ]
+        );
+
+        let err = Recording::from_asciicast("{\"version\": 1, \"width\": 80, \"height\": 24}")
+            .unwrap_err();
+        assert!(err.to_string().contains("version 1"));
+        let err = Recording::from_asciicast("{\"version\": 2, \"width\": 80, \"height\": 24}\n[1]")
+            .unwrap_err();
+        assert!(err.to_string().contains("line 2"));
+    }
+
+    #[test]
+    fn test_ttyrec_frames_timed_from_the_first() {
+        let mut bytes = Vec::new();
+        for (seconds, micros, data) in [(1000u32, 500_000u32, &b"one"[..]), (1002, 0, b"two")] {
+            bytes.extend_from_slice(&seconds.to_le_bytes());
+            bytes.extend_from_slice(&micros.to_le_bytes());
+            bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(data);
+        }
+        // A frame cut off by a crash
+        bytes.extend_from_slice(&[0xe9, 0x03, 0, 0, 0, 0, 0, 0, 99, 0, 0, 0, b'x']);
+
+        let recording = Recording::from_ttyrec(&bytes).unwrap();
+        assert_eq!(
+            recording.frames,
+            [
+                Frame {
+                    time: 0.0,
+                    data: b"one".to_vec()
+                },
+                Frame {
+                    time: 1.5,
+                    data: b"two".to_vec()
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_playback_resumes_when_keys_end_while_paused() {
+        let frames = [b"one".to_vec(), b"two".to_vec()]
+            .map(|data| Frame { time: 0.0, data })
+            .to_vec();
+        let delays = [Duration::from_millis(10); 2];
+        let (sender, receiver) = mpsc::channel(1);
+        sender.send(PlayerKey::Pause).await.unwrap();
+        drop(sender);
+
+        let mut vte_handler = VteHandler::new(
+            Vec::new(),
+            false,
+            ColorTarget::default(),
+            Arc::new(ChildModes::default()),
+        );
+        let playback = play_frames(&frames, &delays, Some(receiver), &mut vte_handler);
+        tokio::time::timeout(Duration::from_secs(5), playback)
+            .await
+            .expect("playback hung")
+            .unwrap();
+        assert_eq!(vte_handler.into_inner(), b"onetwo");
+    }
+
+    #[test]
+    fn test_delays_scaled_and_capped() {
+        let frames = [0.5, 1.0, 11.0, 12.0]
+            .map(|time| Frame {
+                time,
+                data: Vec::new(),
+            })
+            .to_vec();
+        let recording = Recording {
+            frames,
+            ..Recording::default()
+        };
+
+        let millis = |delays: Vec<Duration>| -> Vec<u128> {
+            delays.iter().map(|delay| delay.as_millis()).collect()
+        };
+        assert_eq!(millis(recording.delays(1.0, None)), [500, 500, 10000, 1000]);
+        assert_eq!(
+            millis(recording.delays(2.0, Some(2.0))),
+            [250, 250, 1000, 500]
+        );
+    }
+}
@@ -0,0 +1,541 @@
+/*
+ * gscreen - A true color command wrapper for terminal programs
+ * Copyright (C) 2025 Gamunu Balagalla <gamunu@fastcode.io>
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program; if not, write to the Free Software Foundation, Inc.,
+ * 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+ */
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::time::Instant;
+use vte::{Params, Parser, Perform};
+
+use crate::async_file::AsyncFile;
+
+/// How long to wait for replies. Terminals answer in order, so the wait
+/// normally ends as soon as the DA1 reply, which is asked for last, arrives.
+const PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+/// How long to wait for DA1 once the terminal has started answering, so
+/// slow replies are read here rather than reaching the child as input
+const SLOW_REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The queries, in the order they are answered:
+/// DA2, XTVERSION, XTGETTCAP for RGB and Tc, a truecolor SGR read back
+/// with DECRQSS (then reset), OSC 11 and finally DA1
+const QUERIES: &[u8] = b"\x1b[>c\
+\x1b[>0q\
+\x1bP+q524742\x1b\\\
+\x1bP+q5463\x1b\\\
+\x1b[48:2:1:2:3m\x1bP$qm\x1b\\\x1b[m\
+\x1b]11;?\x1b\\\
+\x1b[c";
+
+/// Enough to tell a cached terminal from another one behind the same
+/// environment, and its current background: DA2, XTVERSION, OSC 11 and DA1
+const IDENTIFY_QUERIES: &[u8] = b"\x1b[>c\x1b[>0q\x1b]11;?\x1b\\\x1b[c";
+
+const ESC: u8 = 0x1b;
+
+/// The truecolor background set before DECRQSS, as it should be read back
+const SGR_PROBE: [u16; 5] = [48, 2, 1, 2, 3];
+
+/// Whether to ask the terminal what it supports
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum ProbeMode {
+    /// Probe once per terminal and reuse the cached results
+    #[default]
+    Auto,
+    /// Probe even if there are cached results, and cache the new ones
+    Fresh,
+    /// Go by environment variables only
+    Off,
+}
+
+/// What the terminal said about itself
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct ProbeReport {
+    /// DA1 attributes, missing if the terminal didn't answer at all
+    pub device_attributes: Option<Vec<u16>>,
+    /// DA2 terminal type, firmware version and ROM cartridge
+    pub secondary_attributes: Option<Vec<u16>>,
+    /// XTVERSION name and version
+    pub version: Option<String>,
+    /// XTGETTCAP reported the RGB or Tc capability
+    pub truecolor_cap: bool,
+    /// A truecolor SGR survived the DECRQSS round trip
+    pub truecolor_sgr: bool,
+    /// The terminal answered the OSC 11 query
+    pub osc_color_queries: bool,
+    /// The background color from that answer, which depends on the theme
+    /// and so isn't cached
+    #[serde(skip)]
+    pub background: Option<(u8, u8, u8)>,
+    /// Input that arrived among the replies, typed while the probe ran
+    #[serde(skip)]
+    pub typeahead: Vec<u8>,
+}
+
+impl ProbeReport {
+    /// Whether the terminal answered the probe at all
+    pub fn answered(&self) -> bool {
+        self.device_attributes.is_some()
+    }
+
+    pub fn truecolor(&self) -> bool {
+        self.truecolor_cap || self.truecolor_sgr
+    }
+
+    /// Whether `other` came from the same terminal, going by what it says
+    /// its type and version are
+    fn same_terminal(&self, other: &ProbeReport) -> bool {
+        self.secondary_attributes == other.secondary_attributes && self.version == other.version
+    }
+
+    /// SGR mouse reports (1006) arrived in xterm patch 277; anything
+    /// answering XTVERSION is newer than that
+    pub fn sgr_mouse(&self) -> bool {
+        let version = self
+            .secondary_attributes
+            .as_ref()
+            .and_then(|attributes| attributes.get(1).copied())
+            .unwrap_or(0);
+        self.version.is_some() || version >= 277
+    }
+}
+
+/// Get the terminal's probe report: from the cache for `Auto` if this
+/// terminal has been probed before, otherwise by probing it. `None` when
+/// probing failed. A terminal that didn't answer is cached too, so it only
+/// costs the timeout once. One that did is asked who it is first, as the
+/// same environment can front a different terminal, say over SSH.
+pub async fn run(mode: ProbeMode, debug: bool) -> Option<ProbeReport> {
+    let identity = terminal_identity();
+    let mut cache = load_cache();
+    let mut typeahead = Vec::new();
+
+    if let Some(cached) = cache.get(&identity).filter(|_| mode == ProbeMode::Auto) {
+        if !cached.answered() {
+            if debug {
+                eprintln!("Using cached probe results for {}", identity);
+            }
+            return Some(cached.clone());
+        }
+
+        match probe(IDENTIFY_QUERIES, PROBE_TIMEOUT).await {
+            Ok(seen) if seen.same_terminal(cached) => {
+                if debug {
+                    eprintln!("Using cached probe results for {}", identity);
+                }
+                return Some(ProbeReport {
+                    background: seen.background,
+                    typeahead: seen.typeahead,
+                    ..cached.clone()
+                });
+            }
+            Ok(mut seen) => {
+                if debug {
+                    eprintln!("Terminal changed since it was probed, probing again");
+                }
+                typeahead = std::mem::take(&mut seen.typeahead);
+            }
+            Err(err) => {
+                if debug {
+                    eprintln!("⚠ Terminal probe failed: {:#}", err);
+                }
+                return None;
+            }
+        }
+    }
+
+    let mut report = match probe(QUERIES, PROBE_TIMEOUT).await {
+        Ok(report) => report,
+        Err(err) => {
+            if debug {
+                eprintln!("⚠ Terminal probe failed: {:#}", err);
+            }
+            return None;
+        }
+    };
+    typeahead.append(&mut report.typeahead);
+    report.typeahead = typeahead;
+
+    cache.insert(identity, report.clone());
+    if let Err(err) = save_cache(&cache) {
+        if debug {
+            eprintln!("⚠ Failed to cache probe results: {:#}", err);
+        }
+    }
+    Some(report)
+}
+
+/// Send `queries`, which must end with DA1, and collect replies until DA1
+/// is answered, or `timeout` runs out without any reply. Stdin and stdout
+/// must be the terminal.
+async fn probe(queries: &[u8], timeout: Duration) -> Result<ProbeReport> {
+    let was_raw = crossterm::terminal::is_raw_mode_enabled().unwrap_or(false);
+    if !was_raw {
+        crossterm::terminal::enable_raw_mode().context("Failed to enable raw mode")?;
+    }
+
+    let result = exchange(queries, timeout).await;
+
+    if !was_raw {
+        let _ = crossterm::terminal::disable_raw_mode();
+    }
+    result
+}
+
+async fn exchange(queries: &[u8], timeout: Duration) -> Result<ProbeReport> {
+    let stdin =
+        AsyncFile::reopen_terminal(libc::STDIN_FILENO).context("Failed to open the terminal")?;
+    let mut stdout = std::io::stdout();
+    stdout
+        .write_all(queries)
+        .and_then(|_| stdout.flush())
+        .context("Failed to send terminal queries")?;
+
+    let start = Instant::now();
+    let mut deadline = start + timeout;
+    let mut parser = Parser::new();
+    let mut collector = ReplyCollector::default();
+    let mut buffer = [0u8; 1024];
+
+    while !collector.report.answered() {
+        match tokio::time::timeout_at(deadline, stdin.read(&mut buffer)).await {
+            Ok(Ok(0)) | Err(_) => break,
+            Ok(Ok(n)) => {
+                // The terminal is answering, give the rest time to arrive
+                deadline = start + SLOW_REPLY_TIMEOUT;
+                collector.advance(&mut parser, &buffer[..n]);
+            }
+            Ok(Err(err)) => return Err(err).context("Failed to read terminal replies"),
+        }
+    }
+
+    Ok(collector.finish())
+}
+
+/// Environment that tells terminals apart, used as the cache key
+fn terminal_identity() -> String {
+    let names = [
+        "TERM",
+        "TERM_PROGRAM",
+        "TERM_PROGRAM_VERSION",
+        "VTE_VERSION",
+        "TMUX",
+        "STY",
+    ];
+    names
+        .iter()
+        .filter_map(|name| {
+            let value = std::env::var(name).ok()?;
+            // Only whether we are inside tmux or screen matters, not which
+            // session
+            Some(match *name {
+                "TMUX" | "STY" => name.to_string(),
+                _ => format!("{}={}", name, value),
+            })
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// `$XDG_CACHE_HOME/gscreen/probe.toml`, falling back to `~/.cache`
+fn cache_path() -> Option<PathBuf> {
+    let cache_home = std::env::var_os("XDG_CACHE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))?;
+    Some(cache_home.join("gscreen").join("probe.toml"))
+}
+
+/// Cached reports by terminal identity. A missing or unreadable cache is
+/// just empty, it only saves a probe.
+fn load_cache() -> HashMap<String, ProbeReport> {
+    cache_path()
+        .and_then(|path| std::fs::read_to_string(path).ok())
+        .and_then(|text| toml::from_str(&text).ok())
+        .unwrap_or_default()
+}
+
+fn save_cache(cache: &HashMap<String, ProbeReport>) -> Result<()> {
+    let path = cache_path().context("No cache directory")?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+    let text = toml::to_string(cache).context("Failed to encode probe results")?;
+    std::fs::write(&path, text).with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Parse an X11 color spec like `rgb:ffff/8080/0000`, with 1 to 4 hex
+/// digits per channel
+pub fn parse_rgb_spec(spec: &str) -> Option<(u8, u8, u8)> {
+    let mut channels = spec.strip_prefix("rgb:")?.split('/').map(|channel| {
+        if channel.is_empty() || channel.len() > 4 {
+            return None;
+        }
+        let value = u32::from_str_radix(channel, 16).ok()?;
+        let max = (1u32 << (4 * channel.len())) - 1;
+        Some(((value * 255 + max / 2) / max) as u8)
+    });
+
+    let rgb = (channels.next()??, channels.next()??, channels.next()??);
+    channels.next().is_none().then_some(rgb)
+}
+
+/// The DCS reply being collected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DcsReply {
+    Version,
+    TermCap { valid: bool },
+    StatusString { valid: bool },
+    Other,
+}
+
+/// Builds a report from the terminal's replies, setting aside anything
+/// else as typeahead
+#[derive(Default)]
+struct ReplyCollector {
+    report: ProbeReport,
+    dcs: Option<DcsReply>,
+    dcs_data: Vec<u8>,
+    /// Raw bytes of the sequence being parsed
+    pending: Vec<u8>,
+    /// The last string ended with the ESC of an `ESC \`
+    string_ended: bool,
+}
+
+impl ReplyCollector {
+    fn advance(&mut self, parser: &mut Parser, bytes: &[u8]) {
+        for &byte in bytes {
+            self.pending.push(byte);
+            parser.advance(self, byte);
+        }
+    }
+
+    /// The report, with the input that wasn't a reply. A lone ESC left over
+    /// is the Escape key, anything longer a reply cut short by the timeout.
+    fn finish(mut self) -> ProbeReport {
+        if self.pending == [ESC] {
+            self.keep_input();
+        }
+        self.report
+    }
+
+    /// The sequence just parsed was typed, not a reply
+    fn keep_input(&mut self) {
+        self.report.typeahead.append(&mut self.pending);
+        self.string_ended = false;
+    }
+
+    fn drop_reply(&mut self) {
+        self.pending.clear();
+        self.string_ended = false;
+    }
+
+    /// Drop or keep a string that just ended. Like the ESC of an `ESC \`,
+    /// which stays pending so the `\` that follows is dropped with it.
+    fn finish_string(&mut self, reply: bool) {
+        let esc_terminated = self.pending.last() == Some(&ESC);
+        if esc_terminated {
+            self.pending.pop();
+        }
+        if reply {
+            self.drop_reply();
+        } else {
+            self.keep_input();
+        }
+        if esc_terminated {
+            self.pending.push(ESC);
+            self.string_ended = true;
+        }
+    }
+
+    fn finish_dcs(&mut self, reply: DcsReply) {
+        let data = String::from_utf8_lossy(&self.dcs_data).into_owned();
+        match reply {
+            DcsReply::Version => self.report.version = Some(data),
+            DcsReply::TermCap { valid: true } => {
+                // `name=value` or just `name`, hex encoded
+                let name = data.split('=').next().unwrap_or_default();
+                if name.eq_ignore_ascii_case("524742") || name.eq_ignore_ascii_case("5463") {
+                    self.report.truecolor_cap = true;
+                }
+            }
+            DcsReply::StatusString { valid: true } => {
+                let values: Vec<u16> = data
+                    .trim_end_matches('m')
+                    .split([';', ':'])
+                    .filter_map(|value| value.parse().ok())
+                    .collect();
+                if values.windows(SGR_PROBE.len()).any(|w| w == SGR_PROBE) {
+                    self.report.truecolor_sgr = true;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Perform for ReplyCollector {
+    fn print(&mut self, _c: char) {
+        self.keep_input();
+    }
+
+    fn execute(&mut self, byte: u8) {
+        if self.pending.len() > 1 && self.pending[0] == ESC {
+            // A control typed while a reply was arriving
+            self.pending.pop();
+            self.report.typeahead.push(byte);
+        } else {
+            self.keep_input();
+        }
+    }
+
+    fn hook(&mut self, params: &Params, intermediates: &[u8], _ignore: bool, action: char) {
+        let valid = params.iter().next() == Some(&[1][..]);
+        self.dcs = Some(match (intermediates, action) {
+            (b">", '|') => DcsReply::Version,
+            (b"+", 'r') => DcsReply::TermCap { valid },
+            (b"$", 'r') => DcsReply::StatusString { valid },
+            _ => DcsReply::Other,
+        });
+        self.dcs_data.clear();
+    }
+
+    fn put(&mut self, byte: u8) {
+        self.dcs_data.push(byte);
+    }
+
+    fn unhook(&mut self) {
+        let reply = self.dcs.take();
+        if let Some(reply) = reply {
+            self.finish_dcs(reply);
+        }
+        self.finish_string(reply.is_some_and(|reply| reply != DcsReply::Other));
+    }
+
+    fn osc_dispatch(&mut self, params: &[&[u8]], _bell_terminated: bool) {
+        let reply = matches!(params, [b"11", _, ..]);
+        if let [b"11", spec, ..] = params {
+            self.report.osc_color_queries = true;
+            self.report.background = parse_rgb_spec(&String::from_utf8_lossy(spec));
+        }
+        self.finish_string(reply);
+    }
+
+    fn csi_dispatch(&mut self, params: &Params, intermediates: &[u8], _ignore: bool, c: char) {
+        let values: Vec<u16> = params.iter().map(|param| param[0]).collect();
+        match (intermediates, c) {
+            (b"?", 'c') => self.report.device_attributes = Some(values),
+            (b">", 'c') => self.report.secondary_attributes = Some(values),
+            _ => {
+                self.keep_input();
+                return;
+            }
+        }
+        self.drop_reply();
+    }
+
+    fn esc_dispatch(&mut self, intermediates: &[u8], _ignore: bool, byte: u8) {
+        if self.string_ended && intermediates.is_empty() && byte == b'\\' {
+            self.drop_reply();
+        } else {
+            self.keep_input();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn collect(replies: &[u8]) -> ProbeReport {
+        let mut collector = ReplyCollector::default();
+        collector.advance(&mut Parser::new(), replies);
+        collector.finish()
+    }
+
+    #[test]
+    fn test_replies_from_a_modern_terminal() {
+        // What xterm 390 answers to QUERIES
+        let report = collect(
+            b"\x1b[>41;390;0c\
+\x1bP>|XTerm(390)\x1b\\\
+\x1bP1+r524742=382F382F38\x1b\\\
+\x1bP0+r5463\x1b\\\
+\x1bP1$r0;48:2::1:2:3m\x1b\\\
+\x1b]11;rgb:1e1e/1e1e/2e2e\x1b\\\
+\x1b[?64;1;2;6;9;15;16;17;18;21;22;28c",
+        );
+
+        assert!(report.answered());
+        assert_eq!(report.secondary_attributes, Some(vec![41, 390, 0]));
+        assert_eq!(report.version.as_deref(), Some("XTerm(390)"));
+        assert!(report.truecolor_cap);
+        assert!(report.truecolor_sgr);
+        assert!(report.osc_color_queries);
+        assert_eq!(report.background, Some((0x1e, 0x1e, 0x2e)));
+        assert!(report.sgr_mouse());
+    }
+
+    #[test]
+    fn test_replies_from_a_basic_terminal() {
+        // Only DA1, and a DECRQSS that lost the truecolor background
+        let report = collect(b"\x1bP1$r0;48;5;16m\x1b\\\x1b[?1;2c");
+
+        assert!(report.answered());
+        assert!(!report.truecolor());
+        assert!(!report.osc_color_queries);
+        assert!(!report.sgr_mouse());
+        assert_eq!(collect(b""), ProbeReport::default());
+    }
+
+    #[test]
+    fn test_typed_input_set_aside_from_replies() {
+        let report = collect(
+            b"ls\x1b[>41;390;0c\x1b[A\
+\x1bP>|XTerm(390)\x1b\\\x1bx\
+\x1b]11;rgb:0000/0000/0000\x07\x03\
+\x1b[?64;1c\xc3\xa9\x1b",
+        );
+
+        assert!(report.answered());
+        assert_eq!(report.version.as_deref(), Some("XTerm(390)"));
+        assert_eq!(report.background, Some((0, 0, 0)));
+        assert_eq!(report.typeahead, b"ls\x1b[A\x1bx\x03\xc3\xa9\x1b");
+
+        // Whatever part of a reply arrived before the timeout is dropped
+        let report = collect(b"q\x1b[>41;39");
+        assert!(!report.answered());
+        assert_eq!(report.typeahead, b"q");
+    }
+
+    #[test]
+    fn test_parse_rgb_spec() {
+        assert_eq!(parse_rgb_spec("rgb:ffff/8080/0000"), Some((255, 128, 0)));
+        assert_eq!(parse_rgb_spec("rgb:f/8/0"), Some((255, 136, 0)));
+        assert_eq!(parse_rgb_spec("rgb:ff/80/00"), Some((255, 128, 0)));
+        assert_eq!(parse_rgb_spec("rgb:ff/80"), None);
+        assert_eq!(parse_rgb_spec("rgb:ff/80/00/00"), None);
+        assert_eq!(parse_rgb_spec("#ff8000"), None);
+    }
+}
@@ -19,15 +19,116 @@
 
 use anyhow::{Context, Result};
 use crossterm::event::{self, Event, KeyCode, KeyEvent};
-use portable_pty::PtyPair;
-use std::io::{Read, Write};
+use portable_pty::PtySize;
+use std::io::{BufWriter, Read, Write};
+use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
 use vte::Parser;
 
-use crate::vte_handler::{InputVteHandler, VteHandler};
+use crate::async_file::AsyncFile;
+use crate::pty::{self, PtyProcess};
+use crate::record::{RecordStage, Recorder, Tee};
+use gscreen::__private::{ChildModes, InputVteHandler, VteHandler};
+use gscreen::color::{ColorTarget, DefaultColors};
+
+/// Size of the stdout buffer, large enough to hold a converted read batch
+const OUTPUT_BUFFER_SIZE: usize = 64 * 1024;
+/// Size of each read from the PTY or stdin
+const READ_BUFFER_SIZE: usize = 4096;
+/// Stop reading input while this much of it is still waiting for the child
+const INPUT_BACKLOG_LIMIT: usize = 64 * 1024;
+/// Chunks a reader thread may queue before it waits for the proxy
+const INPUT_CHANNEL_CAPACITY: usize = 16;
+
+/// How keyboard input from a TTY reaches the child
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum InputMode {
+    /// Forward stdin bytes to the child untouched
+    #[default]
+    Raw,
+    /// Decode keys with crossterm and re-encode them as xterm sequences,
+    /// for outer terminals whose key encoding the child doesn't understand
+    Events,
+}
+
+/// Settings for the I/O proxy
+#[derive(Debug, Clone)]
+pub struct ProxyOptions {
+    pub has_osc_support: bool,
+    pub color_target: ColorTarget,
+    pub input_mode: InputMode,
+    pub default_colors: DefaultColors,
+    /// Whether the outer terminal can report the mouse in SGR encoding
+    pub sgr_mouse: bool,
+    /// Input typed before the proxy started, while the terminal was probed
+    pub typeahead: Vec<u8>,
+}
+
+/// Where input for the child comes from
+enum InputSource {
+    /// The terminal on stdin, opened again and read as it becomes ready
+    Stdin(AsyncFile),
+    /// stdin that isn't a terminal, read by a thread so its flags, which
+    /// whoever started us may share, stay as they are
+    StdinThread(mpsc::Receiver<Vec<u8>>),
+    /// Terminal events decoded by crossterm on a thread and encoded again
+    Events(mpsc::Receiver<Vec<u8>>),
+}
+
+impl InputSource {
+    fn new(stdin_is_tty: bool, input_mode: InputMode, modes: Arc<ChildModes>) -> Self {
+        if stdin_is_tty && input_mode == InputMode::Events {
+            return InputSource::Events(spawn_event_reader(modes));
+        }
+
+        let stdin = stdin_is_tty
+            .then(|| AsyncFile::reopen_terminal(libc::STDIN_FILENO).ok())
+            .flatten();
+        match stdin {
+            Some(stdin) => InputSource::Stdin(stdin),
+            None => InputSource::StdinThread(spawn_stdin_reader()),
+        }
+    }
+
+    /// The next chunk of input, or `None` once it has ended
+    async fn next(&mut self, buf: &mut [u8]) -> Option<Vec<u8>> {
+        match self {
+            InputSource::Stdin(stdin) => match stdin.read(buf).await {
+                Ok(0) | Err(_) => None,
+                Ok(n) => Some(buf[..n].to_vec()),
+            },
+            InputSource::StdinThread(receiver) | InputSource::Events(receiver) => {
+                receiver.recv().await
+            }
+        }
+    }
+
+    /// Whether chunks are bytes from stdin rather than encoded events
+    fn is_raw(&self) -> bool {
+        !matches!(self, InputSource::Events(_))
+    }
+}
+
+/// Copy data between the terminal and the child until the child exits,
+/// recording the session to `recorder` if given. Everything is driven by
+/// readiness, so nothing runs while both are idle.
+pub async fn run_proxy(
+    pty: &mut PtyProcess,
+    options: ProxyOptions,
+    modes: Arc<ChildModes>,
+    mut recorder: Option<&mut Recorder>,
+) -> Result<()> {
+    let ProxyOptions {
+        has_osc_support,
+        color_target,
+        input_mode,
+        default_colors,
+        sgr_mouse,
+        typeahead,
+    } = options;
 
-pub async fn run_proxy(pty_pair: &mut PtyPair, has_osc_support: bool) -> Result<()> {
     // Check if stdin is a TTY
     let stdin_is_tty = crossterm::tty::IsTty::is_tty(&std::io::stdin());
 
@@ -36,292 +137,249 @@ pub async fn run_proxy(pty_pair: &mut PtyPair, has_osc_support: bool) -> Result<
         let _ = crossterm::terminal::enable_raw_mode();
     }
 
-    // Clone the reader for the background thread
-    let mut reader = pty_pair
+    let master_fd = pty
         .master
-        .try_clone_reader()
-        .context("Failed to clone PTY reader")?;
+        .as_raw_fd()
+        .context("PTY master has no file descriptor")?;
+    let master = AsyncFile::new(master_fd).context("Failed to watch the PTY")?;
 
-    // Get a writer handle
-    let writer = pty_pair
-        .master
-        .take_writer()
-        .context("Failed to get PTY writer")?;
+    // Input is written through `master`; this writer is only kept so that
+    // dropping it sends EOF to the child once our own input ends
+    let mut eof_writer = Some(
+        pty.master
+            .take_writer()
+            .context("Failed to get PTY writer")?,
+    );
 
-    // Spawn a thread to handle PTY output -> stdout with VTE parsing
-    let output_handle = thread::spawn(move || {
-        let mut buffer = [0u8; 4096];
-        let stdout = std::io::stdout();
+    let mut window_changes =
+        signal(SignalKind::window_change()).context("Failed to watch for window size changes")?;
 
-        // Create VTE parser and handler with capability info
-        let mut parser = Parser::new();
-        let mut vte_handler = VteHandler::new(Box::new(stdout), has_osc_support);
+    // The outer terminal's replies to color queries only reach the child
+    // when stdin is passed through untouched; otherwise answer them here
+    let forward_color_queries = has_osc_support && stdin_is_tty && input_mode == InputMode::Raw;
 
-        loop {
-            match reader.read(&mut buffer) {
-                Ok(0) => {
-                    // PTY closed, child process exited
-                    break;
-                }
-                Ok(n) => {
-                    // Process bytes through VTE parser
-                    for &byte in &buffer[..n] {
-                        parser.advance(&mut vte_handler, byte);
-                    }
-                }
-                Err(_) => {
-                    // Read error, probably PTY closed
-                    break;
-                }
-            }
-        }
-    });
+    // Create VTE parser and handler with capability info
+    let stdout = BufWriter::with_capacity(OUTPUT_BUFFER_SIZE, std::io::stdout());
+    // Converted output is recorded from a copy of what goes to stdout
+    let record_converted = recorder
+        .as_ref()
+        .is_some_and(|recorder| recorder.stage() == RecordStage::Converted);
+    let (writer, converted): (Box<dyn Write + Send>, _) = if record_converted {
+        let (tee, copy) = Tee::new(stdout);
+        (Box::new(tee), Some(copy))
+    } else {
+        (Box::new(stdout), None)
+    };
+    let mut parser = Parser::new();
+    let mut vte_handler = VteHandler::new(
+        writer,
+        forward_color_queries,
+        color_target,
+        Arc::clone(&modes),
+    )
+    .with_default_colors(default_colors);
+    // Events mode decodes mouse reports with crossterm, which handles
+    // coordinates past 223 only in SGR form, and encodes them again for the
+    // child. Terminals without SGR get the child's own encoding.
+    if stdin_is_tty && input_mode == InputMode::Events && sgr_mouse {
+        vte_handler = vte_handler.with_mouse_reencoding();
+    }
 
-    // Handle input differently based on whether stdin is a TTY
-    if stdin_is_tty {
-        // TTY mode: use crossterm event handling for interactive input
-        let mut last_size = crossterm::terminal::size().unwrap_or((80, 24));
-        let mut writer = writer;
+    let mut input = InputSource::new(stdin_is_tty, input_mode, modes);
+    let input_is_raw = input.is_raw();
+    let mut input_open = true;
+    let mut input_parser = Parser::new();
+    let mut input_handler = InputVteHandler::new();
+    if let Some(recorder) = recorder.as_deref_mut() {
+        recorder.input(&typeahead);
+    }
+    // Input read but not yet accepted by the child
+    let mut to_child = typeahead;
 
-        loop {
-            // Check if output thread is still running
-            if output_handle.is_finished() {
-                break;
-            }
+    let mut output_buf = [0u8; READ_BUFFER_SIZE];
+    let mut input_buf = [0u8; READ_BUFFER_SIZE];
 
-            // Handle input events
-            if let Ok(Some(input)) = read_user_input().await {
-                // Write to PTY writer
-                if writer.write_all(&input).is_err() {
-                    break;
-                }
-                if writer.flush().is_err() {
-                    break;
+    loop {
+        tokio::select! {
+            read = master.read(&mut output_buf) => match read {
+                // EOF, or EIO once the child has closed its side
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    if let Some(recorder) = recorder.as_deref_mut() {
+                        if recorder.stage() == RecordStage::Original {
+                            recorder.output(&output_buf[..n]);
+                        }
+                    }
+                    // Process bytes through VTE parser
+                    vte_handler.process(&mut parser, &output_buf[..n]);
+                    // Queries answered on the terminal's behalf
+                    to_child.append(&mut vte_handler.take_replies());
+                    // Show the whole batch at once
+                    // The child is likely still running, main hangs up on it
+                    vte_handler.flush().context("Failed to write to the terminal")?;
+                    if let (Some(recorder), Some(converted)) = (recorder.as_deref_mut(), &converted) {
+                        if let Ok(mut bytes) = converted.lock() {
+                            recorder.output(&bytes);
+                            bytes.clear();
+                        }
+                    }
                 }
-            }
-
-            // Handle window resize
-            if let Ok(current_size) = crossterm::terminal::size() {
-                if current_size != last_size {
-                    last_size = current_size;
-                    let size = portable_pty::PtySize {
-                        rows: current_size.1,
-                        cols: current_size.0,
-                        pixel_width: 0,
-                        pixel_height: 0,
-                    };
-                    let _ = pty_pair.master.resize(size);
+            },
+            written = master.write(&to_child), if !to_child.is_empty() => match written {
+                Ok(n) => {
+                    to_child.drain(..n);
                 }
-            }
-
-            // Small delay to prevent busy waiting
-            tokio::time::sleep(Duration::from_millis(1)).await;
-        }
-    } else {
-        // Non-TTY mode: copy stdin to PTY with VTE processing for terminal responses
-        let writer = writer;
-        let stdin_thread = thread::spawn(move || {
-            let mut stdin = std::io::stdin();
-            let mut buffer = [0u8; 4096];
-
-            // Create VTE parser and input handler for processing terminal responses
-            let mut parser = Parser::new();
-            let mut input_handler = InputVteHandler::new(Box::new(writer));
-
-            loop {
-                match stdin.read(&mut buffer) {
-                    Ok(0) => break, // EOF
-                    Ok(n) => {
+                Err(_) => break,
+            },
+            chunk = input.next(&mut input_buf),
+                if input_open && to_child.len() < INPUT_BACKLOG_LIMIT => match chunk {
+                Some(bytes) => {
+                    let mut bytes = if input_is_raw {
                         // Process bytes through input VTE parser to handle terminal responses
-                        for &byte in &buffer[..n] {
-                            parser.advance(&mut input_handler, byte);
-                        }
+                        input_handler.process(&mut input_parser, &bytes);
+                        input_handler.take_output()
+                    } else {
+                        bytes
+                    };
+                    if let Some(recorder) = recorder.as_deref_mut() {
+                        recorder.input(&bytes);
                     }
-                    Err(_) => break,
+                    to_child.append(&mut bytes);
+                }
+                None => input_open = false,
+            },
+            _ = window_changes.recv() => {
+                let size = resize_pty(pty);
+                if let Some(recorder) = recorder.as_deref_mut() {
+                    recorder.resize(size);
                 }
             }
-        });
-
-        // Wait for either the output or stdin thread to finish
-        loop {
-            if output_handle.is_finished() || stdin_thread.is_finished() {
-                break;
-            }
-            tokio::time::sleep(Duration::from_millis(10)).await;
         }
 
-        let _ = stdin_thread.join();
+        // Once all of our input has reached the child, tell it there is no more
+        if !input_open && to_child.is_empty() {
+            eof_writer.take();
+        }
     }
 
-    // Wait for output thread to finish
-    let _ = output_handle.join();
-
     Ok(())
 }
 
-async fn read_user_input() -> Result<Option<Vec<u8>>> {
-    // Poll for events with faster response for better mouse performance
-    if event::poll(Duration::from_millis(1)).context("Failed to poll for events")? {
-        match event::read().context("Failed to read event")? {
-            Event::Key(KeyEvent {
-                code: KeyCode::Char(c),
-                modifiers,
-                ..
-            }) => {
-                // Handle special key combinations
-                if modifiers.contains(event::KeyModifiers::CONTROL) {
-                    match c {
-                        'c' => return Ok(Some(vec![0x03])), // Ctrl+C
-                        'd' => return Ok(Some(vec![0x04])), // Ctrl+D
-                        'z' => return Ok(Some(vec![0x1a])), // Ctrl+Z
-                        _ => {
-                            // Other Ctrl combinations
-                            let ctrl_char = (c as u8)
-                                .to_ascii_lowercase()
-                                .wrapping_sub(b'a')
-                                .wrapping_add(1);
-                            return Ok(Some(vec![ctrl_char]));
-                        }
+/// Give the child the outer terminal's new size, which also sends it SIGWINCH
+fn resize_pty(pty: &PtyProcess) -> PtySize {
+    let size = pty::terminal_size();
+    let _ = pty.master.resize(size);
+    size
+}
+
+/// Read stdin on a thread, for a stdin that isn't polled
+fn spawn_stdin_reader() -> mpsc::Receiver<Vec<u8>> {
+    let (sender, receiver) = mpsc::channel(INPUT_CHANNEL_CAPACITY);
+    thread::spawn(move || {
+        let mut stdin = std::io::stdin();
+        let mut buffer = [0u8; READ_BUFFER_SIZE];
+
+        loop {
+            match stdin.read(&mut buffer) {
+                Ok(0) | Err(_) => break, // EOF
+                Ok(n) => {
+                    if sender.blocking_send(buffer[..n].to_vec()).is_err() {
+                        break;
                     }
-                } else {
-                    // Regular character
-                    return Ok(Some(c.to_string().into_bytes()));
                 }
             }
-            Event::Key(KeyEvent { code, .. }) => {
-                // Handle special keys
-                let bytes = match code {
-                    KeyCode::Enter => vec![b'\r'],
-                    KeyCode::Tab => vec![b'\t'],
-                    KeyCode::Backspace => vec![0x7f],
-                    KeyCode::Delete => vec![0x1b, b'[', b'3', b'~'],
-                    KeyCode::Up => vec![0x1b, b'[', b'A'],
-                    KeyCode::Down => vec![0x1b, b'[', b'B'],
-                    KeyCode::Right => vec![0x1b, b'[', b'C'],
-                    KeyCode::Left => vec![0x1b, b'[', b'D'],
-                    KeyCode::Home => vec![0x1b, b'[', b'H'],
-                    KeyCode::End => vec![0x1b, b'[', b'F'],
-                    KeyCode::PageUp => vec![0x1b, b'[', b'5', b'~'],
-                    KeyCode::PageDown => vec![0x1b, b'[', b'6', b'~'],
-                    KeyCode::Esc => vec![0x1b],
-                    KeyCode::F(n) => {
-                        // Function keys F1-F12
-                        match n {
-                            1 => vec![0x1b, b'O', b'P'],
-                            2 => vec![0x1b, b'O', b'Q'],
-                            3 => vec![0x1b, b'O', b'R'],
-                            4 => vec![0x1b, b'O', b'S'],
-                            5 => vec![0x1b, b'[', b'1', b'5', b'~'],
-                            6 => vec![0x1b, b'[', b'1', b'7', b'~'],
-                            7 => vec![0x1b, b'[', b'1', b'8', b'~'],
-                            8 => vec![0x1b, b'[', b'1', b'9', b'~'],
-                            9 => vec![0x1b, b'[', b'2', b'0', b'~'],
-                            10 => vec![0x1b, b'[', b'2', b'1', b'~'],
-                            11 => vec![0x1b, b'[', b'2', b'3', b'~'],
-                            12 => vec![0x1b, b'[', b'2', b'4', b'~'],
-                            _ => return Ok(None),
-                        }
-                    }
-                    _ => return Ok(None),
-                };
-                return Ok(Some(bytes));
+        }
+    });
+    receiver
+}
+
+/// Read terminal events on a thread, crossterm's reader blocks until one
+/// arrives, and encode them for the child
+fn spawn_event_reader(modes: Arc<ChildModes>) -> mpsc::Receiver<Vec<u8>> {
+    let (sender, receiver) = mpsc::channel(INPUT_CHANNEL_CAPACITY);
+    thread::spawn(move || {
+        while let Ok(event) = event::read() {
+            if let Some(bytes) = encode_event(event, &modes) {
+                if sender.blocking_send(bytes).is_err() {
+                    break;
+                }
             }
-            Event::Mouse(mouse_event) => {
-                // Handle mouse events - convert to appropriate escape sequences
-                use crossterm::event::{MouseButton, MouseEventKind};
+        }
+    });
+    receiver
+}
 
-                match mouse_event.kind {
-                    MouseEventKind::Down(MouseButton::Left) => {
-                        // Mouse button down - send SGR mouse report
-                        let sequence = format!(
-                            "\x1b[<0;{};{}M",
-                            mouse_event.column + 1,
-                            mouse_event.row + 1
-                        );
-                        return Ok(Some(sequence.into_bytes()));
-                    }
-                    MouseEventKind::Up(MouseButton::Left) => {
-                        // Mouse button up
-                        let sequence = format!(
-                            "\x1b[<0;{};{}m",
-                            mouse_event.column + 1,
-                            mouse_event.row + 1
-                        );
-                        return Ok(Some(sequence.into_bytes()));
-                    }
-                    MouseEventKind::Down(MouseButton::Right) => {
-                        let sequence = format!(
-                            "\x1b[<2;{};{}M",
-                            mouse_event.column + 1,
-                            mouse_event.row + 1
-                        );
-                        return Ok(Some(sequence.into_bytes()));
-                    }
-                    MouseEventKind::Up(MouseButton::Right) => {
-                        let sequence = format!(
-                            "\x1b[<2;{};{}m",
-                            mouse_event.column + 1,
-                            mouse_event.row + 1
-                        );
-                        return Ok(Some(sequence.into_bytes()));
-                    }
-                    MouseEventKind::Down(MouseButton::Middle) => {
-                        let sequence = format!(
-                            "\x1b[<1;{};{}M",
-                            mouse_event.column + 1,
-                            mouse_event.row + 1
-                        );
-                        return Ok(Some(sequence.into_bytes()));
-                    }
-                    MouseEventKind::Up(MouseButton::Middle) => {
-                        let sequence = format!(
-                            "\x1b[<1;{};{}m",
-                            mouse_event.column + 1,
-                            mouse_event.row + 1
-                        );
-                        return Ok(Some(sequence.into_bytes()));
-                    }
-                    MouseEventKind::Drag(MouseButton::Left) => {
-                        let sequence = format!(
-                            "\x1b[<32;{};{}M",
-                            mouse_event.column + 1,
-                            mouse_event.row + 1
-                        );
-                        return Ok(Some(sequence.into_bytes()));
-                    }
-                    MouseEventKind::Moved => {
-                        // Mouse movement without button pressed - don't send by default
-                        // Most terminal applications only care about movement during drag
-                        return Ok(None);
-                    }
-                    MouseEventKind::ScrollDown => {
-                        let sequence = format!(
-                            "\x1b[<65;{};{}M",
-                            mouse_event.column + 1,
-                            mouse_event.row + 1
-                        );
-                        return Ok(Some(sequence.into_bytes()));
-                    }
-                    MouseEventKind::ScrollUp => {
-                        let sequence = format!(
-                            "\x1b[<64;{};{}M",
-                            mouse_event.column + 1,
-                            mouse_event.row + 1
-                        );
-                        return Ok(Some(sequence.into_bytes()));
-                    }
+/// Encode a terminal event the way xterm would send it to the child
+fn encode_event(event: Event, modes: &ChildModes) -> Option<Vec<u8>> {
+    match event {
+        Event::Key(KeyEvent {
+            code: KeyCode::Char(c),
+            modifiers,
+            ..
+        }) => {
+            // Handle special key combinations
+            if modifiers.contains(event::KeyModifiers::CONTROL) {
+                match c {
+                    'c' => Some(vec![0x03]), // Ctrl+C
+                    'd' => Some(vec![0x04]), // Ctrl+D
+                    'z' => Some(vec![0x1a]), // Ctrl+Z
                     _ => {
-                        // Other mouse events
-                        return Ok(None);
+                        // Other Ctrl combinations
+                        let ctrl_char = (c as u8)
+                            .to_ascii_lowercase()
+                            .wrapping_sub(b'a')
+                            .wrapping_add(1);
+                        Some(vec![ctrl_char])
                     }
                 }
+            } else {
+                // Regular character
+                Some(c.to_string().into_bytes())
             }
-            _ => {
-                // Other events (resize, etc.)
-                return Ok(None);
-            }
         }
+        Event::Key(KeyEvent { code, .. }) => {
+            // Handle special keys
+            let bytes = match code {
+                KeyCode::Enter => vec![b'\r'],
+                KeyCode::Tab => vec![b'\t'],
+                KeyCode::Backspace => vec![0x7f],
+                KeyCode::Delete => vec![0x1b, b'[', b'3', b'~'],
+                KeyCode::Up => vec![0x1b, b'[', b'A'],
+                KeyCode::Down => vec![0x1b, b'[', b'B'],
+                KeyCode::Right => vec![0x1b, b'[', b'C'],
+                KeyCode::Left => vec![0x1b, b'[', b'D'],
+                KeyCode::Home => vec![0x1b, b'[', b'H'],
+                KeyCode::End => vec![0x1b, b'[', b'F'],
+                KeyCode::PageUp => vec![0x1b, b'[', b'5', b'~'],
+                KeyCode::PageDown => vec![0x1b, b'[', b'6', b'~'],
+                KeyCode::Esc => vec![0x1b],
+                KeyCode::F(n) => {
+                    // Function keys F1-F12
+                    match n {
+                        1 => vec![0x1b, b'O', b'P'],
+                        2 => vec![0x1b, b'O', b'Q'],
+                        3 => vec![0x1b, b'O', b'R'],
+                        4 => vec![0x1b, b'O', b'S'],
+                        5 => vec![0x1b, b'[', b'1', b'5', b'~'],
+                        6 => vec![0x1b, b'[', b'1', b'7', b'~'],
+                        7 => vec![0x1b, b'[', b'1', b'8', b'~'],
+                        8 => vec![0x1b, b'[', b'1', b'9', b'~'],
+                        9 => vec![0x1b, b'[', b'2', b'0', b'~'],
+                        10 => vec![0x1b, b'[', b'2', b'1', b'~'],
+                        11 => vec![0x1b, b'[', b'2', b'3', b'~'],
+                        12 => vec![0x1b, b'[', b'2', b'4', b'~'],
+                        _ => return None,
+                    }
+                }
+                _ => return None,
+            };
+            Some(bytes)
+        }
+        // Only what the child asked for, in the encoding it asked for
+        Event::Mouse(mouse_event) => modes.encode_mouse(&mouse_event),
+        // Pasted text, bracketed again if the child asked for it
+        Event::Paste(text) => Some(modes.encode_paste(&text)),
+        // Other events (resize, etc.)
+        _ => None,
     }
-
-    Ok(None)
 }
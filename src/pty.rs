@@ -18,39 +18,136 @@
  */
 
 use anyhow::{Context, Result};
-use portable_pty::{CommandBuilder, PtyPair, PtySize};
+use crossterm::terminal::WindowSize;
+use portable_pty::{Child, CommandBuilder, MasterPty, PtySize};
 use std::collections::HashMap;
+use std::thread;
+use std::time::{Duration, Instant};
 
-pub fn create_pty_with_command(command: &str, args: &[String]) -> Result<PtyPair> {
-    // Create a new PTY with the actual terminal size
-    let (cols, rows) = crossterm::terminal::size().unwrap_or((80, 24)); // fallback to 80x24 if detection fails
+/// TERM given to the child unless configured otherwise
+const DEFAULT_TERM: &str = "xterm-256color";
+
+/// Columns and rows used when the outer terminal's size is unknown
+const FALLBACK_SIZE: (u16, u16) = (80, 24);
+
+/// Exit code offset used by shells to report a child killed by a signal
+#[cfg(unix)]
+const SIGNAL_EXIT_BASE: i32 = 128;
 
-    let pty_size = PtySize {
+/// How long a child that is hung up on gets to exit before it is killed
+#[cfg(unix)]
+const HANGUP_GRACE: Duration = Duration::from_millis(500);
+#[cfg(unix)]
+const HANGUP_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A command running inside a PTY, together with the master side used to drive it
+pub struct PtyProcess {
+    pub master: Box<dyn MasterPty + Send>,
+    pub child: Box<dyn Child + Send + Sync>,
+}
+
+/// The outer terminal's size in cells and, where it reports them, pixels,
+/// which image-capable programs need to scale their output
+pub fn terminal_size() -> PtySize {
+    if let Some(size) = crossterm::terminal::window_size()
+        .ok()
+        .and_then(pty_size_from_window)
+    {
+        return size;
+    }
+
+    // No usable TIOCGWINSZ, crossterm can still ask tput for the cell size
+    let (cols, rows) = crossterm::terminal::size().unwrap_or(FALLBACK_SIZE);
+    PtySize {
         rows,
         cols,
         pixel_width: 0,
         pixel_height: 0,
-    };
+    }
+}
 
-    let pty_system = portable_pty::native_pty_system();
-    let pty_pair = pty_system.openpty(pty_size).context("Failed to open PTY")?;
+fn pty_size_from_window(size: WindowSize) -> Option<PtySize> {
+    if size.columns == 0 || size.rows == 0 {
+        return None;
+    }
 
-    // Set up environment variables for true color support
-    let mut env_vars = HashMap::new();
+    Some(PtySize {
+        rows: size.rows,
+        cols: size.columns,
+        pixel_width: size.width,
+        pixel_height: size.height,
+    })
+}
 
-    // Copy current environment
-    for (key, value) in std::env::vars() {
-        env_vars.insert(key, value);
+/// Changes to the child's environment on top of the true color variables
+#[derive(Debug, Clone, Default)]
+pub struct ChildEnv {
+    /// TERM for the child, xterm-256color if not set
+    pub term: Option<String>,
+    /// Extra variables, which win over the built-in ones
+    pub vars: HashMap<String, String>,
+    /// Variables removed last, whether inherited, built-in or configured
+    pub unset: Vec<String>,
+}
+
+impl ChildEnv {
+    /// The TERM the child ends up with
+    pub fn term(&self) -> &str {
+        self.vars
+            .get("TERM")
+            .or(self.term.as_ref())
+            .map_or(DEFAULT_TERM, String::as_str)
     }
+}
+
+/// Parse a `KEY=VALUE` assignment as given to `--env`
+pub fn parse_env_assignment(text: &str) -> Result<(String, String), String> {
+    match text.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("expected KEY=VALUE, got '{}'", text)),
+    }
+}
+
+/// The child's full environment: ours, the true color variables, then the
+/// configured changes
+fn build_environment(
+    inherited: impl IntoIterator<Item = (String, String)>,
+    child_env: &ChildEnv,
+) -> HashMap<String, String> {
+    // Copy current environment
+    let mut env_vars: HashMap<String, String> = inherited.into_iter().collect();
 
     // Override specific variables for true color support
     env_vars.insert("COLORTERM".to_string(), "truecolor".to_string());
-    env_vars.insert("TERM".to_string(), "xterm-256color".to_string());
+    env_vars.insert("TERM".to_string(), child_env.term().to_string());
 
     // Force true color support
     env_vars.insert("FORCE_COLOR".to_string(), "1".to_string());
     env_vars.insert("CLICOLOR_FORCE".to_string(), "1".to_string());
 
+    // Configured variables
+    env_vars.extend(child_env.vars.clone());
+    for key in &child_env.unset {
+        env_vars.remove(key);
+    }
+
+    env_vars
+}
+
+pub fn create_pty_with_command(
+    command: &str,
+    args: &[String],
+    child_env: &ChildEnv,
+) -> Result<PtyProcess> {
+    // Create a new PTY with the actual terminal size
+    let pty_size = terminal_size();
+
+    let pty_system = portable_pty::native_pty_system();
+    let pty_pair = pty_system.openpty(pty_size).context("Failed to open PTY")?;
+
+    // Set up environment variables for true color support
+    let env_vars = build_environment(std::env::vars(), child_env);
+
     // Build the command with arguments
     let mut cmd_builder = CommandBuilder::new(command);
     cmd_builder.args(args);
@@ -60,16 +157,202 @@ pub fn create_pty_with_command(command: &str, args: &[String]) -> Result<PtyPair
         cmd_builder.cwd(current_dir);
     }
 
-    // Set environment variables
+    // Set environment variables, replacing the inherited ones so unset
+    // variables stay unset
+    cmd_builder.env_clear();
     for (key, value) in env_vars {
         cmd_builder.env(&key, &value);
     }
 
     // Spawn the command in the PTY slave
-    let _child = pty_pair
+    let child = pty_pair
         .slave
         .spawn_command(cmd_builder)
         .context("Failed to spawn command in PTY")?;
 
-    Ok(pty_pair)
+    // Drop our copy of the slave so the master sees EOF once the child exits
+    drop(pty_pair.slave);
+
+    Ok(PtyProcess {
+        master: pty_pair.master,
+        child,
+    })
+}
+
+/// Wait for the child to exit and return the status gscreen should exit with.
+/// A child killed by signal N is reported as 128+N, like a shell would.
+pub fn wait_for_exit_code(child: &mut (dyn Child + Send + Sync)) -> Result<i32> {
+    #[cfg(unix)]
+    if let Some(pid) = child.process_id() {
+        return wait_pid(pid as libc::pid_t);
+    }
+
+    let status = child.wait().context("Failed to wait for child process")?;
+    Ok(status.exit_code() as i32)
+}
+
+/// End a child that may still be running, after the proxy gave up on it,
+/// and return its status like `wait_for_exit_code`. Its process group gets
+/// SIGHUP, as if the terminal had gone away, and SIGKILL if it is still
+/// there after `HANGUP_GRACE`.
+pub fn hang_up_child(child: &mut (dyn Child + Send + Sync)) -> Result<i32> {
+    #[cfg(unix)]
+    if let Some(pid) = child.process_id() {
+        let pid = pid as libc::pid_t;
+        let deadline = Instant::now() + HANGUP_GRACE;
+        unsafe { libc::kill(-pid, libc::SIGHUP) };
+        while Instant::now() < deadline {
+            if let Some(code) = try_wait_pid(pid)? {
+                return Ok(code);
+            }
+            thread::sleep(HANGUP_POLL_INTERVAL);
+        }
+        unsafe { libc::kill(-pid, libc::SIGKILL) };
+        return wait_pid(pid);
+    }
+
+    let _ = child.kill();
+    wait_for_exit_code(child)
+}
+
+#[cfg(unix)]
+fn try_wait_pid(pid: libc::pid_t) -> Result<Option<i32>> {
+    let mut status: libc::c_int = 0;
+    match unsafe { libc::waitpid(pid, &mut status, libc::WNOHANG) } {
+        0 => Ok(None),
+        ret if ret == pid => Ok(Some(exit_code_from_wait_status(status))),
+        _ => Err(std::io::Error::last_os_error()).context("Failed to wait for child process"),
+    }
+}
+
+#[cfg(unix)]
+fn wait_pid(pid: libc::pid_t) -> Result<i32> {
+    // portable-pty only reports the signal name, so reap the child ourselves
+    // to get at the raw wait status
+    let mut status: libc::c_int = 0;
+    loop {
+        let ret = unsafe { libc::waitpid(pid, &mut status, 0) };
+        if ret == pid {
+            return Ok(exit_code_from_wait_status(status));
+        }
+        let err = std::io::Error::last_os_error();
+        if err.kind() != std::io::ErrorKind::Interrupted {
+            return Err(err).context("Failed to wait for child process");
+        }
+    }
+}
+
+#[cfg(unix)]
+fn exit_code_from_wait_status(status: libc::c_int) -> i32 {
+    if libc::WIFEXITED(status) {
+        libc::WEXITSTATUS(status)
+    } else if libc::WIFSIGNALED(status) {
+        SIGNAL_EXIT_BASE + libc::WTERMSIG(status)
+    } else {
+        1
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exit_code_from_wait_status() {
+        // Normal exits carry the code in the second byte
+        assert_eq!(exit_code_from_wait_status(0), 0);
+        assert_eq!(exit_code_from_wait_status(3 << 8), 3);
+        assert_eq!(exit_code_from_wait_status(255 << 8), 255);
+
+        // Signal deaths map to 128 + signal number
+        assert_eq!(exit_code_from_wait_status(libc::SIGKILL), 137);
+        assert_eq!(exit_code_from_wait_status(libc::SIGTERM), 143);
+    }
+
+    #[test]
+    fn test_hang_up_child_kills_if_ignored() {
+        use std::os::unix::process::CommandExt;
+        use std::process::Command;
+
+        let mut child = Command::new("sleep")
+            .arg("10")
+            .process_group(0)
+            .spawn()
+            .unwrap();
+        assert_eq!(hang_up_child(&mut child).unwrap(), 128 + libc::SIGHUP);
+
+        let mut stubborn = Command::new("sh")
+            .args(["-c", "trap '' HUP; sleep 10"])
+            .process_group(0)
+            .spawn()
+            .unwrap();
+        // Give the shell time to ignore SIGHUP
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(hang_up_child(&mut stubborn).unwrap(), 128 + libc::SIGKILL);
+    }
+
+    #[test]
+    fn test_build_environment_applies_changes_in_order() {
+        let inherited = [
+            ("HOME".to_string(), "/home/me".to_string()),
+            ("TERM".to_string(), "linux".to_string()),
+            ("PAGER".to_string(), "more".to_string()),
+        ];
+        let child_env = ChildEnv {
+            term: Some("tmux-256color".to_string()),
+            vars: HashMap::from([
+                ("PAGER".to_string(), "less".to_string()),
+                ("FORCE_COLOR".to_string(), "3".to_string()),
+            ]),
+            unset: vec!["CLICOLOR_FORCE".to_string(), "HOME".to_string()],
+        };
+
+        let env = build_environment(inherited, &child_env);
+        assert_eq!(env["TERM"], "tmux-256color");
+        assert_eq!(env["COLORTERM"], "truecolor");
+        assert_eq!(env["PAGER"], "less");
+        assert_eq!(env["FORCE_COLOR"], "3");
+        assert!(!env.contains_key("CLICOLOR_FORCE"));
+        assert!(!env.contains_key("HOME"));
+
+        let defaults = build_environment([], &ChildEnv::default());
+        assert_eq!(defaults["TERM"], "xterm-256color");
+        assert_eq!(defaults["FORCE_COLOR"], "1");
+    }
+
+    #[test]
+    fn test_parse_env_assignment() {
+        assert_eq!(
+            parse_env_assignment("A=b=c"),
+            Ok(("A".to_string(), "b=c".to_string()))
+        );
+        assert_eq!(
+            parse_env_assignment("EMPTY="),
+            Ok(("EMPTY".to_string(), String::new()))
+        );
+        assert!(parse_env_assignment("NOVALUE").is_err());
+        assert!(parse_env_assignment("=x").is_err());
+    }
+
+    #[test]
+    fn test_pty_size_from_window_keeps_pixels() {
+        let window = WindowSize {
+            rows: 50,
+            columns: 160,
+            width: 1280,
+            height: 800,
+        };
+        let size = pty_size_from_window(window).unwrap();
+        assert_eq!((size.rows, size.cols), (50, 160));
+        assert_eq!((size.pixel_width, size.pixel_height), (1280, 800));
+
+        // Some PTYs report 0x0, which is no size at all
+        let empty = WindowSize {
+            rows: 0,
+            columns: 0,
+            width: 0,
+            height: 0,
+        };
+        assert!(pty_size_from_window(empty).is_none());
+    }
 }
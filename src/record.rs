@@ -0,0 +1,297 @@
+/*
+ * gscreen - A true color command wrapper for terminal programs
+ * Copyright (C) 2025 Gamunu Balagalla <gamunu@fastcode.io>
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program; if not, write to the Free Software Foundation, Inc.,
+ * 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+ */
+
+use anyhow::{Context, Result};
+use portable_pty::PtySize;
+use serde_json::json;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// Which output goes into a recording
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum RecordStage {
+    /// What reached the terminal, after color conversion
+    #[default]
+    Converted,
+    /// What the command wrote, before color conversion
+    Original,
+}
+
+/// Writes a session as an asciicast v2 file: a JSON header line followed by
+/// one `[time, code, data]` line per event
+pub struct Recorder<W: Write = BufWriter<File>> {
+    writer: W,
+    start: Instant,
+    stage: RecordStage,
+    input: bool,
+    /// Columns and rows last recorded
+    size: (u16, u16),
+    /// Trailing bytes of a UTF-8 character split across reads, per stream
+    output_tail: Vec<u8>,
+    input_tail: Vec<u8>,
+    /// The first write error, after which nothing more is recorded
+    error: Option<io::Error>,
+}
+
+/// What the recording's header describes
+pub struct Session<'a> {
+    pub size: PtySize,
+    /// TERM the command runs with
+    pub term: &'a str,
+    pub command: &'a str,
+}
+
+impl Recorder {
+    pub fn create(path: &Path, session: &Session, stage: RecordStage, input: bool) -> Result<Self> {
+        let file = File::create(path)
+            .with_context(|| format!("Failed to create recording {}", path.display()))?;
+        Recorder::new(BufWriter::new(file), session, stage, input)
+            .with_context(|| format!("Failed to write recording {}", path.display()))
+    }
+}
+
+impl<W: Write> Recorder<W> {
+    pub fn new(mut writer: W, session: &Session, stage: RecordStage, input: bool) -> Result<Self> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or_default();
+        let header = json!({
+            "version": 2,
+            "width": session.size.cols,
+            "height": session.size.rows,
+            "timestamp": timestamp,
+            "command": session.command,
+            "env": {
+                "TERM": session.term,
+                "SHELL": std::env::var("SHELL").unwrap_or_default(),
+            },
+        });
+        writeln!(writer, "{}", header)?;
+
+        Ok(Self {
+            writer,
+            start: Instant::now(),
+            stage,
+            input,
+            size: (session.size.cols, session.size.rows),
+            output_tail: Vec::new(),
+            input_tail: Vec::new(),
+            error: None,
+        })
+    }
+
+    pub fn stage(&self) -> RecordStage {
+        self.stage
+    }
+
+    /// Record output shown on the terminal
+    pub fn output(&mut self, bytes: &[u8]) {
+        let text = decode_utf8(&mut self.output_tail, bytes);
+        self.event("o", &text);
+    }
+
+    /// Record input sent to the command, if input is being recorded
+    pub fn input(&mut self, bytes: &[u8]) {
+        if self.input {
+            let text = decode_utf8(&mut self.input_tail, bytes);
+            self.event("i", &text);
+        }
+    }
+
+    /// Record a new terminal size. SIGWINCH doesn't always mean a change,
+    /// so only actual changes are recorded.
+    pub fn resize(&mut self, size: PtySize) {
+        if (size.cols, size.rows) != self.size {
+            self.size = (size.cols, size.rows);
+            self.event("r", &format!("{}x{}", size.cols, size.rows));
+        }
+    }
+
+    /// Flush the recording, returning the first error met while writing it
+    pub fn finish(mut self) -> io::Result<W> {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    fn event(&mut self, code: &str, data: &str) {
+        if self.error.is_some() || data.is_empty() {
+            return;
+        }
+        let time = self.start.elapsed().as_secs_f64();
+        // Microseconds, like asciinema itself
+        let time = (time * 1e6).round() / 1e6;
+        if let Err(err) = writeln!(self.writer, "{}", json!([time, code, data])) {
+            self.error = Some(err);
+        }
+    }
+}
+
+/// Decode `bytes` after whatever was left in `tail`, keeping an incomplete
+/// character at the end for next time. Invalid bytes become U+FFFD.
+fn decode_utf8(tail: &mut Vec<u8>, bytes: &[u8]) -> String {
+    tail.extend_from_slice(bytes);
+    let mut text = String::new();
+    let mut rest = &tail[..];
+
+    loop {
+        match std::str::from_utf8(rest) {
+            Ok(valid) => {
+                text.push_str(valid);
+                rest = &[];
+                break;
+            }
+            Err(err) => {
+                let (valid, after) = rest.split_at(err.valid_up_to());
+                // Checked by from_utf8 just now
+                text.push_str(std::str::from_utf8(valid).unwrap_or_default());
+                match err.error_len() {
+                    Some(len) => {
+                        text.push(char::REPLACEMENT_CHARACTER);
+                        rest = &after[len..];
+                    }
+                    None => {
+                        rest = after;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    *tail = rest.to_vec();
+    text
+}
+
+/// A writer that keeps a copy of everything written through it, so output
+/// can be recorded after conversion
+pub struct Tee<W> {
+    inner: W,
+    copy: Arc<Mutex<Vec<u8>>>,
+}
+
+impl<W: Write> Tee<W> {
+    /// The writer and a handle to the copied bytes
+    pub fn new(inner: W) -> (Self, Arc<Mutex<Vec<u8>>>) {
+        let copy = Arc::new(Mutex::new(Vec::new()));
+        let tee = Tee {
+            inner,
+            copy: Arc::clone(&copy),
+        };
+        (tee, copy)
+    }
+}
+
+impl<W: Write> Write for Tee<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        if let Ok(mut copy) = self.copy.lock() {
+            copy.extend_from_slice(&buf[..n]);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    #[test]
+    fn test_recording_is_asciicast_v2() {
+        let session = Session {
+            size: PtySize {
+                rows: 24,
+                cols: 80,
+                pixel_width: 0,
+                pixel_height: 0,
+            },
+            term: "xterm-256color",
+            command: "htop",
+        };
+        let mut recorder =
+            Recorder::new(Vec::new(), &session, RecordStage::Original, true).unwrap();
+        // "é" split across two reads
+        recorder.output(b"\x1b[1mcaf\xc3");
+        recorder.output(b"\xa9\xff");
+        recorder.input(b"q");
+        recorder.resize(session.size);
+        recorder.resize(PtySize {
+            rows: 50,
+            cols: 132,
+            ..session.size
+        });
+
+        let recording = String::from_utf8(recorder.finish().unwrap()).unwrap();
+        let lines: Vec<Value> = recording
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+
+        let header = &lines[0];
+        assert_eq!(header["version"], 2);
+        assert_eq!(
+            (header["width"].clone(), header["height"].clone()),
+            (80.into(), 24.into())
+        );
+        assert_eq!(header["env"]["TERM"], "xterm-256color");
+        assert!(header["timestamp"].as_u64().unwrap() > 0);
+
+        let events: Vec<(&str, &str)> = lines[1..]
+            .iter()
+            .map(|event| (event[1].as_str().unwrap(), event[2].as_str().unwrap()))
+            .collect();
+        assert_eq!(
+            events,
+            [
+                ("o", "\x1b[1mcaf"),
+                ("o", "é\u{fffd}"),
+                ("i", "q"),
+                ("r", "132x50")
+            ]
+        );
+        assert!(lines[1..]
+            .iter()
+            .all(|event| event[0].as_f64().unwrap() >= 0.0));
+    }
+
+    #[test]
+    fn test_input_left_out_unless_asked_for() {
+        let session = Session {
+            size: PtySize::default(),
+            term: "xterm",
+            command: "sh",
+        };
+        let mut recorder =
+            Recorder::new(Vec::new(), &session, RecordStage::Converted, false).unwrap();
+        recorder.input(b"secret\r");
+        let recording = recorder.finish().unwrap();
+        assert_eq!(recording.iter().filter(|&&byte| byte == b'\n').count(), 1);
+    }
+}
@@ -0,0 +1,1046 @@
+/*
+ * gscreen - A true color command wrapper for terminal programs
+ * Copyright (C) 2025 Gamunu Balagalla <gamunu@fastcode.io>
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program; if not, write to the Free Software Foundation, Inc.,
+ * 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+ */
+
+use std::collections::VecDeque;
+use std::ops::Range;
+use unicode_width::UnicodeWidthChar;
+use vte::{Params, Parser, Perform};
+
+use crate::color::Color;
+use crate::vte_handler::parse_extended_color;
+
+/// Lines kept after scrolling off the top of the main screen
+const DEFAULT_SCROLLBACK_LIMIT: usize = 1000;
+/// Columns between the tab stops a reset sets
+const TAB_WIDTH: usize = 8;
+
+/// How a cell is drawn, as set by SGR
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Attributes {
+    /// `None` is the terminal's default color
+    pub foreground: Option<Color>,
+    pub background: Option<Color>,
+    pub bold: bool,
+    pub dim: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub blink: bool,
+    pub inverse: bool,
+    pub hidden: bool,
+    pub strikethrough: bool,
+}
+
+/// One character cell of the grid
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cell {
+    pub ch: char,
+    /// Combining characters drawn over `ch`
+    pub combining: Vec<char>,
+    /// Columns taken: 2 for a wide character, whose second cell has width 0
+    pub width: u8,
+    pub attributes: Attributes,
+}
+
+impl Cell {
+    /// An empty cell. Erasing keeps only the background, like xterm does.
+    fn blank(attributes: &Attributes) -> Self {
+        Cell {
+            ch: ' ',
+            combining: Vec::new(),
+            width: 1,
+            attributes: Attributes {
+                background: attributes.background,
+                ..Attributes::default()
+            },
+        }
+    }
+}
+
+impl Default for Cell {
+    fn default() -> Self {
+        Cell::blank(&Attributes::default())
+    }
+}
+
+/// A line of the grid
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Row {
+    pub cells: Vec<Cell>,
+    /// Whether the text runs on into the next row because of autowrap
+    pub wrapped: bool,
+}
+
+impl Row {
+    fn blank(cols: usize, attributes: &Attributes) -> Self {
+        Row {
+            cells: vec![Cell::blank(attributes); cols],
+            wrapped: false,
+        }
+    }
+
+    /// The row's text, a wide character counted once
+    pub fn text(&self) -> String {
+        let mut text = String::with_capacity(self.cells.len());
+        for cell in self.cells.iter().filter(|cell| cell.width > 0) {
+            text.push(cell.ch);
+            text.extend(&cell.combining);
+        }
+        text
+    }
+}
+
+/// Character sets that can be designated as G0 and G1
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum Charset {
+    #[default]
+    Ascii,
+    /// DEC special graphics, the line drawing set
+    DecGraphics,
+}
+
+impl Charset {
+    fn map(self, c: char) -> char {
+        if self == Charset::Ascii {
+            return c;
+        }
+        match c {
+            '_' => ' ',
+            '`' => '◆',
+            'a' => '▒',
+            'b' => '␉',
+            'c' => '␌',
+            'd' => '␍',
+            'e' => '␊',
+            'f' => '°',
+            'g' => '±',
+            'h' => '␤',
+            'i' => '␋',
+            'j' => '┘',
+            'k' => '┐',
+            'l' => '┌',
+            'm' => '└',
+            'n' => '┼',
+            'o' => '⎺',
+            'p' => '⎻',
+            'q' => '─',
+            'r' => '⎼',
+            's' => '⎽',
+            't' => '├',
+            'u' => '┤',
+            'v' => '┴',
+            'w' => '┬',
+            'x' => '│',
+            'y' => '≤',
+            'z' => '≥',
+            '{' => 'π',
+            '|' => '≠',
+            '}' => '£',
+            '~' => '·',
+            c => c,
+        }
+    }
+}
+
+/// Cursor position and the state that DECSC saves along with it
+#[derive(Debug, Clone, Copy, Default)]
+struct Cursor {
+    row: usize,
+    col: usize,
+    attributes: Attributes,
+    /// A character went into the last column; the next one wraps first
+    pending_wrap: bool,
+    origin_mode: bool,
+    charsets: [Charset; 2],
+    /// Which of G0 and G1 is in use
+    shift_out: bool,
+}
+
+/// A grid of cells kept up to date from terminal output, the way a
+/// terminal would show it. Feed it the same bytes as `VteHandler`.
+pub struct Screen {
+    rows: usize,
+    cols: usize,
+    grid: Vec<Row>,
+    /// The main screen while the alternate one is shown, and the other way
+    /// round
+    inactive_grid: Vec<Row>,
+    alternate: bool,
+    scrollback: VecDeque<Row>,
+    scrollback_limit: usize,
+    cursor: Cursor,
+    /// DECSC state for the main and the alternate screen
+    saved_cursors: [Option<Cursor>; 2],
+    /// First and last row of the scroll region
+    scroll_top: usize,
+    scroll_bottom: usize,
+    tab_stops: Vec<bool>,
+    autowrap: bool,
+    insert_mode: bool,
+    cursor_visible: bool,
+    /// For REP, which repeats the last printed character
+    last_char: Option<char>,
+}
+
+impl Screen {
+    pub fn new(rows: usize, cols: usize) -> Self {
+        let (rows, cols) = (rows.max(1), cols.max(1));
+        Screen {
+            rows,
+            cols,
+            grid: vec![Row::blank(cols, &Attributes::default()); rows],
+            inactive_grid: vec![Row::blank(cols, &Attributes::default()); rows],
+            alternate: false,
+            scrollback: VecDeque::new(),
+            scrollback_limit: DEFAULT_SCROLLBACK_LIMIT,
+            cursor: Cursor::default(),
+            saved_cursors: [None; 2],
+            scroll_top: 0,
+            scroll_bottom: rows - 1,
+            tab_stops: default_tab_stops(cols),
+            autowrap: true,
+            insert_mode: false,
+            cursor_visible: true,
+            last_char: None,
+        }
+    }
+
+    /// Keep up to `lines` lines that scroll off the top of the main screen
+    pub fn with_scrollback_limit(mut self, lines: usize) -> Self {
+        self.scrollback_limit = lines;
+        self
+    }
+
+    /// Feed output through `parser`
+    pub fn process(&mut self, parser: &mut Parser, bytes: &[u8]) {
+        for &byte in bytes {
+            parser.advance(self, byte);
+        }
+    }
+
+    /// Rows and columns
+    pub fn size(&self) -> (usize, usize) {
+        (self.rows, self.cols)
+    }
+
+    /// Row and column of the cursor, from 0
+    pub fn cursor(&self) -> (usize, usize) {
+        (self.cursor.row, self.cursor.col)
+    }
+
+    pub fn cursor_visible(&self) -> bool {
+        self.cursor_visible
+    }
+
+    pub fn alternate_screen(&self) -> bool {
+        self.alternate
+    }
+
+    pub fn row(&self, row: usize) -> &Row {
+        &self.grid[row]
+    }
+
+    pub fn cell(&self, row: usize, col: usize) -> &Cell {
+        &self.grid[row].cells[col]
+    }
+
+    /// Lines that scrolled off the top, oldest first
+    pub fn scrollback(&self) -> impl Iterator<Item = &Row> {
+        self.scrollback.iter()
+    }
+
+    /// The text on screen, one line per row without trailing blanks
+    pub fn contents(&self) -> String {
+        let lines: Vec<String> = self
+            .grid
+            .iter()
+            .map(|row| row.text().trim_end().to_string())
+            .collect();
+        lines.join("\n")
+    }
+
+    /// Change the size. Rows are dropped from the bottom, unless the cursor
+    /// is there, in which case the top ones scroll off; lines aren't
+    /// reflowed.
+    pub fn resize(&mut self, rows: usize, cols: usize) {
+        let (rows, cols) = (rows.max(1), cols.max(1));
+
+        if rows < self.rows && self.cursor.row >= rows {
+            let excess = self.cursor.row + 1 - rows;
+            for row in self.grid.drain(..excess) {
+                if !self.alternate {
+                    push_scrollback(&mut self.scrollback, self.scrollback_limit, row);
+                }
+            }
+            self.cursor.row -= excess;
+        }
+        for grid in [&mut self.grid, &mut self.inactive_grid] {
+            grid.resize(rows, Row::blank(cols, &Attributes::default()));
+            for row in grid.iter_mut() {
+                row.cells.resize(cols, Cell::default());
+                repair_wide(row, cols.saturating_sub(1)..cols);
+            }
+        }
+
+        let old_cols = self.tab_stops.len();
+        self.tab_stops.resize(cols, false);
+        for col in old_cols..cols {
+            self.tab_stops[col] = col % TAB_WIDTH == 0;
+        }
+
+        self.rows = rows;
+        self.cols = cols;
+        self.scroll_top = 0;
+        self.scroll_bottom = rows - 1;
+        self.cursor.row = self.cursor.row.min(rows - 1);
+        self.cursor.col = self.cursor.col.min(cols - 1);
+        self.cursor.pending_wrap = false;
+    }
+
+    fn print_char(&mut self, c: char) {
+        let c = self.cursor.charsets[self.cursor.shift_out as usize].map(c);
+        let width = match c.width() {
+            Some(0) | None => return self.add_combining(c),
+            Some(width) => width.min(2),
+        };
+        if width > self.cols {
+            return;
+        }
+
+        if self.cursor.pending_wrap && self.autowrap {
+            self.wrap_line();
+        }
+        // A wide character that doesn't fit in the last column
+        if self.cursor.col + width > self.cols {
+            if self.autowrap {
+                self.wrap_line();
+            } else {
+                self.cursor.col = self.cols - width;
+            }
+        }
+
+        let (row, col) = (self.cursor.row, self.cursor.col);
+        let attributes = self.cursor.attributes;
+        let cols = self.cols;
+        let line = &mut self.grid[row];
+        if self.insert_mode {
+            line.cells[col..].rotate_right(width);
+        }
+        line.cells[col] = Cell {
+            ch: c,
+            combining: Vec::new(),
+            width: width as u8,
+            attributes,
+        };
+        if width == 2 {
+            line.cells[col + 1] = Cell {
+                ch: ' ',
+                combining: Vec::new(),
+                width: 0,
+                attributes,
+            };
+        }
+        let repair = if self.insert_mode {
+            0..cols
+        } else {
+            col..col + width
+        };
+        repair_wide(line, repair);
+
+        self.last_char = Some(c);
+        if col + width >= cols {
+            self.cursor.col = cols - 1;
+            self.cursor.pending_wrap = self.autowrap;
+        } else {
+            self.cursor.col = col + width;
+        }
+    }
+
+    /// Put a zero width character on the cell printed last
+    fn add_combining(&mut self, c: char) {
+        let (row, mut col) = (self.cursor.row, self.cursor.col);
+        if !self.cursor.pending_wrap {
+            if col == 0 {
+                return;
+            }
+            col -= 1;
+        }
+        let cells = &mut self.grid[row].cells;
+        if cells[col].width == 0 && col > 0 {
+            col -= 1;
+        }
+        cells[col].combining.push(c);
+    }
+
+    fn wrap_line(&mut self) {
+        self.grid[self.cursor.row].wrapped = true;
+        self.cursor.col = 0;
+        self.linefeed();
+    }
+
+    fn linefeed(&mut self) {
+        self.cursor.pending_wrap = false;
+        if self.cursor.row == self.scroll_bottom {
+            self.scroll_up(1);
+        } else if self.cursor.row + 1 < self.rows {
+            self.cursor.row += 1;
+        }
+    }
+
+    fn reverse_index(&mut self) {
+        self.cursor.pending_wrap = false;
+        if self.cursor.row == self.scroll_top {
+            self.scroll_down(1);
+        } else if self.cursor.row > 0 {
+            self.cursor.row -= 1;
+        }
+    }
+
+    /// Scroll the region up, new lines appearing at the bottom. Lines leaving
+    /// the top of the main screen go to the scrollback.
+    fn scroll_up(&mut self, count: usize) {
+        let count = count.min(self.scroll_bottom - self.scroll_top + 1);
+        let blank = Row::blank(self.cols, &self.cursor.attributes);
+        for _ in 0..count {
+            let row = self.grid.remove(self.scroll_top);
+            if self.scroll_top == 0 && !self.alternate {
+                push_scrollback(&mut self.scrollback, self.scrollback_limit, row);
+            }
+            self.grid.insert(self.scroll_bottom, blank.clone());
+        }
+    }
+
+    /// Scroll the region down, new lines appearing at the top
+    fn scroll_down(&mut self, count: usize) {
+        let count = count.min(self.scroll_bottom - self.scroll_top + 1);
+        let blank = Row::blank(self.cols, &self.cursor.attributes);
+        for _ in 0..count {
+            self.grid.remove(self.scroll_bottom);
+            self.grid.insert(self.scroll_top, blank.clone());
+        }
+    }
+
+    /// Move the cursor to a row and column from 0, relative to the scroll
+    /// region in origin mode
+    fn goto(&mut self, row: usize, col: usize) {
+        let (top, bottom) = if self.cursor.origin_mode {
+            (self.scroll_top, self.scroll_bottom)
+        } else {
+            (0, self.rows - 1)
+        };
+        self.cursor.row = (top + row).min(bottom);
+        self.cursor.col = col.min(self.cols - 1);
+        self.cursor.pending_wrap = false;
+    }
+
+    /// Move up or down without leaving the scroll region if inside it
+    fn move_vertically(&mut self, rows: isize) {
+        let row = self.cursor.row;
+        let (top, bottom) = if (self.scroll_top..=self.scroll_bottom).contains(&row) {
+            (self.scroll_top, self.scroll_bottom)
+        } else {
+            (0, self.rows - 1)
+        };
+        self.cursor.row = row.saturating_add_signed(rows).clamp(top, bottom);
+        self.cursor.pending_wrap = false;
+    }
+
+    fn move_horizontally(&mut self, cols: isize) {
+        self.cursor.col = self
+            .cursor
+            .col
+            .saturating_add_signed(cols)
+            .min(self.cols - 1);
+        self.cursor.pending_wrap = false;
+    }
+
+    fn tab(&mut self, count: usize, forward: bool) {
+        for _ in 0..count {
+            let col = self.cursor.col;
+            self.cursor.col = if forward {
+                (col + 1..self.cols)
+                    .find(|&col| self.tab_stops[col])
+                    .unwrap_or(self.cols - 1)
+            } else {
+                (0..col).rev().find(|&col| self.tab_stops[col]).unwrap_or(0)
+            };
+        }
+        self.cursor.pending_wrap = false;
+    }
+
+    /// Blank `cols` of `row`, in the cursor's background
+    fn erase(&mut self, row: usize, cols: Range<usize>) {
+        let blank = Cell::blank(&self.cursor.attributes);
+        let line = &mut self.grid[row];
+        line.cells[cols.clone()].fill(blank);
+        repair_wide(line, cols);
+    }
+
+    fn erase_rows(&mut self, rows: Range<usize>) {
+        for row in rows {
+            self.erase(row, 0..self.cols);
+            self.grid[row].wrapped = false;
+        }
+    }
+
+    fn erase_in_display(&mut self, mode: u16) {
+        let (row, col) = (self.cursor.row, self.cursor.col);
+        match mode {
+            0 => {
+                self.erase(row, col..self.cols);
+                self.erase_rows(row + 1..self.rows);
+            }
+            1 => {
+                self.erase_rows(0..row);
+                self.erase(row, 0..col + 1);
+            }
+            2 => self.erase_rows(0..self.rows),
+            3 => self.scrollback.clear(),
+            _ => {}
+        }
+    }
+
+    fn erase_in_line(&mut self, mode: u16) {
+        let (row, col) = (self.cursor.row, self.cursor.col);
+        match mode {
+            0 => self.erase(row, col..self.cols),
+            1 => self.erase(row, 0..col + 1),
+            2 => self.erase(row, 0..self.cols),
+            _ => {}
+        }
+    }
+
+    /// ICH and DCH: shift the rest of the line right or left from the cursor
+    fn shift_cells(&mut self, count: usize, insert: bool) {
+        let (row, col) = (self.cursor.row, self.cursor.col);
+        let count = count.min(self.cols - col);
+        let blank = Cell::blank(&self.cursor.attributes);
+        let line = &mut self.grid[row];
+        let cells = &mut line.cells[col..];
+        if insert {
+            cells.rotate_right(count);
+            cells[..count].fill(blank);
+        } else {
+            cells.rotate_left(count);
+            let len = cells.len();
+            cells[len - count..].fill(blank);
+        }
+        repair_wide(line, 0..self.cols);
+        self.cursor.pending_wrap = false;
+    }
+
+    /// IL and DL: shift the region's lines from the cursor down or up
+    fn shift_rows(&mut self, count: usize, insert: bool) {
+        let row = self.cursor.row;
+        if !(self.scroll_top..=self.scroll_bottom).contains(&row) {
+            return;
+        }
+        let count = count.min(self.scroll_bottom - row + 1);
+        let blank = Row::blank(self.cols, &self.cursor.attributes);
+        for _ in 0..count {
+            if insert {
+                self.grid.remove(self.scroll_bottom);
+                self.grid.insert(row, blank.clone());
+            } else {
+                self.grid.remove(row);
+                self.grid.insert(self.scroll_bottom, blank.clone());
+            }
+        }
+        self.cursor.col = 0;
+        self.cursor.pending_wrap = false;
+    }
+
+    fn set_scroll_region(&mut self, top: usize, bottom: usize) {
+        let bottom = if bottom == 0 {
+            self.rows
+        } else {
+            bottom.min(self.rows)
+        };
+        let top = top.max(1);
+        if top < bottom {
+            self.scroll_top = top - 1;
+            self.scroll_bottom = bottom - 1;
+            self.goto(0, 0);
+        }
+    }
+
+    fn save_cursor(&mut self) {
+        self.saved_cursors[self.alternate as usize] = Some(self.cursor);
+    }
+
+    fn restore_cursor(&mut self) {
+        self.cursor = self.saved_cursors[self.alternate as usize].unwrap_or_default();
+        self.cursor.row = self.cursor.row.min(self.rows - 1);
+        self.cursor.col = self.cursor.col.min(self.cols - 1);
+    }
+
+    fn switch_screen(&mut self, alternate: bool) {
+        if self.alternate != alternate {
+            std::mem::swap(&mut self.grid, &mut self.inactive_grid);
+            self.alternate = alternate;
+        }
+    }
+
+    fn set_private_mode(&mut self, mode: u16, enabled: bool) {
+        match mode {
+            6 => {
+                self.cursor.origin_mode = enabled;
+                self.goto(0, 0);
+            }
+            7 => self.autowrap = enabled,
+            25 => self.cursor_visible = enabled,
+            47 => self.switch_screen(enabled),
+            1047 => {
+                // Leaving clears the alternate screen
+                if !enabled && self.alternate {
+                    self.erase_rows(0..self.rows);
+                }
+                self.switch_screen(enabled);
+            }
+            1048 if enabled => self.save_cursor(),
+            1048 => self.restore_cursor(),
+            1049 if enabled => {
+                self.save_cursor();
+                self.switch_screen(true);
+                self.erase_rows(0..self.rows);
+            }
+            1049 => {
+                self.switch_screen(false);
+                self.restore_cursor();
+            }
+            _ => {}
+        }
+    }
+
+    fn handle_sgr(&mut self, params: &Params) {
+        let params: Vec<&[u16]> = params.iter().collect();
+        let attributes = &mut self.cursor.attributes;
+        if params.is_empty() {
+            *attributes = Attributes::default();
+            return;
+        }
+
+        let mut index = 0;
+        while index < params.len() {
+            let mut step = 1;
+            match params[index][0] {
+                0 => *attributes = Attributes::default(),
+                1 => attributes.bold = true,
+                2 => attributes.dim = true,
+                3 => attributes.italic = true,
+                4 => attributes.underline = params[index].get(1) != Some(&0),
+                5 | 6 => attributes.blink = true,
+                7 => attributes.inverse = true,
+                8 => attributes.hidden = true,
+                9 => attributes.strikethrough = true,
+                21 => attributes.underline = true,
+                22 => {
+                    attributes.bold = false;
+                    attributes.dim = false;
+                }
+                23 => attributes.italic = false,
+                24 => attributes.underline = false,
+                25 => attributes.blink = false,
+                27 => attributes.inverse = false,
+                28 => attributes.hidden = false,
+                29 => attributes.strikethrough = false,
+                code @ 30..=37 => attributes.foreground = Some(Color::Indexed(code as u8 - 30)),
+                code @ 40..=47 => attributes.background = Some(Color::Indexed(code as u8 - 40)),
+                code @ 90..=97 => attributes.foreground = Some(Color::Indexed(code as u8 - 82)),
+                code @ 100..=107 => attributes.background = Some(Color::Indexed(code as u8 - 92)),
+                39 => attributes.foreground = None,
+                49 => attributes.background = None,
+                code @ (38 | 48 | 58) => match parse_extended_color(&params, index) {
+                    Some((color, span)) => {
+                        step = span;
+                        match code {
+                            38 => attributes.foreground = Some(color),
+                            48 => attributes.background = Some(color),
+                            // Underline colors aren't kept
+                            _ => {}
+                        }
+                    }
+                    // Nothing after it can be trusted to be a parameter
+                    None => break,
+                },
+                _ => {}
+            }
+            index += step;
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = Screen::new(self.rows, self.cols).with_scrollback_limit(self.scrollback_limit);
+    }
+}
+
+impl Perform for Screen {
+    fn print(&mut self, c: char) {
+        self.print_char(c);
+    }
+
+    fn execute(&mut self, byte: u8) {
+        match byte {
+            0x08 => self.move_horizontally(-1),
+            0x09 => self.tab(1, true),
+            0x0a..=0x0c => self.linefeed(),
+            0x0d => {
+                self.cursor.col = 0;
+                self.cursor.pending_wrap = false;
+            }
+            0x0e => self.cursor.shift_out = true,
+            0x0f => self.cursor.shift_out = false,
+            _ => {}
+        }
+    }
+
+    fn csi_dispatch(&mut self, params: &Params, intermediates: &[u8], ignore: bool, c: char) {
+        if ignore {
+            return;
+        }
+        let values: Vec<u16> = params.iter().map(|param| param[0]).collect();
+        // The nth parameter, with 0 or a missing one meaning `default`
+        let arg = |n: usize, default: usize| match values.get(n) {
+            Some(&value) if value > 0 => value as usize,
+            _ => default,
+        };
+        let count = arg(0, 1);
+
+        match (intermediates, c) {
+            (b"?", 'h') | (b"?", 'l') => {
+                for &mode in &values {
+                    self.set_private_mode(mode, c == 'h');
+                }
+            }
+            (b"", 'h') | (b"", 'l') if values.contains(&4) => self.insert_mode = c == 'h',
+            (b"", _) => match c {
+                'A' => self.move_vertically(-(count as isize)),
+                'B' | 'e' => self.move_vertically(count as isize),
+                'C' | 'a' => self.move_horizontally(count as isize),
+                'D' => self.move_horizontally(-(count as isize)),
+                'E' => {
+                    self.move_vertically(count as isize);
+                    self.cursor.col = 0;
+                }
+                'F' => {
+                    self.move_vertically(-(count as isize));
+                    self.cursor.col = 0;
+                }
+                'G' | '`' => {
+                    self.cursor.col = (count - 1).min(self.cols - 1);
+                    self.cursor.pending_wrap = false;
+                }
+                'H' | 'f' => self.goto(arg(0, 1) - 1, arg(1, 1) - 1),
+                'd' => self.goto(count - 1, self.cursor.col),
+                'I' => self.tab(count, true),
+                'Z' => self.tab(count, false),
+                'J' => self.erase_in_display(values.first().copied().unwrap_or(0)),
+                'K' => self.erase_in_line(values.first().copied().unwrap_or(0)),
+                'X' => {
+                    let (row, col) = (self.cursor.row, self.cursor.col);
+                    self.erase(row, col..(col + count).min(self.cols));
+                }
+                '@' => self.shift_cells(count, true),
+                'P' => self.shift_cells(count, false),
+                'L' => self.shift_rows(count, true),
+                'M' => self.shift_rows(count, false),
+                'S' => self.scroll_up(count),
+                'T' => self.scroll_down(count),
+                'b' => {
+                    if let Some(c) = self.last_char {
+                        for _ in 0..count {
+                            self.print_char(c);
+                        }
+                    }
+                }
+                'g' => match values.first().copied().unwrap_or(0) {
+                    0 => self.tab_stops[self.cursor.col] = false,
+                    3 => self.tab_stops.fill(false),
+                    _ => {}
+                },
+                'm' => self.handle_sgr(params),
+                'r' => self.set_scroll_region(arg(0, 1), arg(1, 0)),
+                's' => self.save_cursor(),
+                'u' => self.restore_cursor(),
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    fn esc_dispatch(&mut self, intermediates: &[u8], _ignore: bool, byte: u8) {
+        match (intermediates, byte) {
+            (b"", b'7') => self.save_cursor(),
+            (b"", b'8') => self.restore_cursor(),
+            (b"", b'D') => self.linefeed(),
+            (b"", b'E') => {
+                self.cursor.col = 0;
+                self.linefeed();
+            }
+            (b"", b'M') => self.reverse_index(),
+            (b"", b'H') => self.tab_stops[self.cursor.col] = true,
+            (b"", b'c') => self.reset(),
+            // DECALN, the alignment test pattern
+            (b"#", b'8') => {
+                let fill = Cell {
+                    ch: 'E',
+                    ..Cell::default()
+                };
+                for row in &mut self.grid {
+                    row.cells.fill(fill.clone());
+                    row.wrapped = false;
+                }
+                self.scroll_top = 0;
+                self.scroll_bottom = self.rows - 1;
+                self.goto(0, 0);
+            }
+            (b"(", charset) | (b")", charset) => {
+                let slot = (intermediates == b")") as usize;
+                self.cursor.charsets[slot] = match charset {
+                    b'0' => Charset::DecGraphics,
+                    _ => Charset::Ascii,
+                };
+            }
+            _ => {}
+        }
+    }
+}
+
+fn default_tab_stops(cols: usize) -> Vec<bool> {
+    (0..cols)
+        .map(|col| col > 0 && col % TAB_WIDTH == 0)
+        .collect()
+}
+
+fn push_scrollback(scrollback: &mut VecDeque<Row>, limit: usize, row: Row) {
+    if limit == 0 {
+        return;
+    }
+    if scrollback.len() == limit {
+        scrollback.pop_front();
+    }
+    scrollback.push_back(row);
+}
+
+/// Blank halves of wide characters whose other half was overwritten, in
+/// and right next to `cols`
+fn repair_wide(row: &mut Row, cols: Range<usize>) {
+    let len = row.cells.len();
+    let start = cols.start.saturating_sub(1);
+    let end = (cols.end + 1).min(len);
+    for col in start..end {
+        let cell = &row.cells[col];
+        let broken = match cell.width {
+            2 => row.cells.get(col + 1).is_none_or(|next| next.width != 0),
+            0 => col == 0 || row.cells[col - 1].width != 2,
+            _ => false,
+        };
+        if broken {
+            row.cells[col] = Cell::blank(&cell.attributes);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn screen_after(rows: usize, cols: usize, input: &[u8]) -> Screen {
+        let mut screen = Screen::new(rows, cols);
+        screen.process(&mut Parser::new(), input);
+        screen
+    }
+
+    /// A fixture case: a name, a size, input with `\e`, `\r`, `\n`, `\t`
+    /// and `\xNN` escapes, the expected screen between `|`s and optionally
+    /// the cursor position, counted from 1 like CUP does
+    struct Fixture {
+        name: String,
+        rows: usize,
+        cols: usize,
+        input: Vec<u8>,
+        screen: Vec<String>,
+        cursor: Option<(usize, usize)>,
+    }
+
+    fn unescape(text: &str) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let mut chars = text.chars();
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                let mut buffer = [0u8; 4];
+                bytes.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+                continue;
+            }
+            match chars.next() {
+                Some('e') => bytes.push(0x1b),
+                Some('r') => bytes.push(b'\r'),
+                Some('n') => bytes.push(b'\n'),
+                Some('t') => bytes.push(b'\t'),
+                Some('b') => bytes.push(0x08),
+                Some('x') => {
+                    let hex: String = chars.by_ref().take(2).collect();
+                    bytes.push(u8::from_str_radix(&hex, 16).unwrap());
+                }
+                Some('\\') => bytes.push(b'\\'),
+                other => panic!("unknown escape \\{:?}", other),
+            }
+        }
+        bytes
+    }
+
+    fn parse_fixtures(text: &str) -> Vec<Fixture> {
+        let mut fixtures: Vec<Fixture> = Vec::new();
+        for line in text.lines() {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix("=== ") {
+                fixtures.push(Fixture {
+                    name: name.to_string(),
+                    rows: 0,
+                    cols: 0,
+                    input: Vec::new(),
+                    screen: Vec::new(),
+                    cursor: None,
+                });
+                continue;
+            }
+
+            let fixture = fixtures.last_mut().expect("fixture must start with ===");
+            if let Some(size) = line.strip_prefix("size ") {
+                let (cols, rows) = size.split_once('x').unwrap();
+                fixture.cols = cols.parse().unwrap();
+                fixture.rows = rows.parse().unwrap();
+            } else if let Some(input) = line.strip_prefix("input ") {
+                fixture.input.extend(unescape(input));
+            } else if let Some(cursor) = line.strip_prefix("cursor ") {
+                let (row, col) = cursor.split_once(',').unwrap();
+                fixture.cursor = Some((row.parse().unwrap(), col.parse().unwrap()));
+            } else if let Some(row) = line.strip_prefix('|').and_then(|row| row.strip_suffix('|')) {
+                fixture.screen.push(row.to_string());
+            } else {
+                panic!("{}: can't parse {:?}", fixture.name, line);
+            }
+        }
+        fixtures
+    }
+
+    #[test]
+    fn test_vttest_fixtures() {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/screen");
+        let mut paths: Vec<_> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        paths.sort();
+        assert!(!paths.is_empty());
+
+        let mut failures = Vec::new();
+        for path in paths {
+            let text = std::fs::read_to_string(&path).unwrap();
+            let file = path.file_name().unwrap().to_string_lossy().into_owned();
+            for fixture in parse_fixtures(&text) {
+                assert_eq!(fixture.screen.len(), fixture.rows, "{}: rows", fixture.name);
+                let screen = screen_after(fixture.rows, fixture.cols, &fixture.input);
+                let shown: Vec<String> = (0..fixture.rows)
+                    .map(|row| screen.row(row).text())
+                    .collect();
+                let cursor = screen.cursor();
+                let cursor = (cursor.0 + 1, cursor.1 + 1);
+
+                if shown != fixture.screen || fixture.cursor.is_some_and(|c| c != cursor) {
+                    failures.push(format!(
+                        "{} / {}\nexpected cursor {:?}, got {:?}\nexpected:\n|{}|\ngot:\n|{}|",
+                        file,
+                        fixture.name,
+                        fixture.cursor,
+                        cursor,
+                        fixture.screen.join("|\n|"),
+                        shown.join("|\n|")
+                    ));
+                }
+            }
+        }
+        assert!(failures.is_empty(), "\n{}", failures.join("\n\n"));
+    }
+
+    #[test]
+    fn test_attributes_follow_sgr() {
+        let screen = screen_after(
+            2,
+            10,
+            b"\x1b[1;38;2;255;0;0mA\x1b[22;48;5;17mB\x1b[0;7mC\x1b[mD\x1b[44m\x1b[K",
+        );
+
+        let a = screen.cell(0, 0).attributes;
+        assert!(a.bold);
+        assert_eq!(a.foreground, Some(Color::Rgb(255, 0, 0)));
+        let b = screen.cell(0, 1).attributes;
+        assert!(!b.bold);
+        assert_eq!(b.foreground, Some(Color::Rgb(255, 0, 0)));
+        assert_eq!(b.background, Some(Color::Indexed(17)));
+        assert_eq!(
+            screen.cell(0, 2).attributes,
+            Attributes {
+                inverse: true,
+                ..Attributes::default()
+            }
+        );
+        assert_eq!(screen.cell(0, 3).attributes, Attributes::default());
+        // Erased cells take the background only
+        assert_eq!(
+            screen.cell(0, 9).attributes.background,
+            Some(Color::Indexed(4))
+        );
+    }
+
+    #[test]
+    fn test_scrollback_and_alternate_screen() {
+        let mut screen = Screen::new(2, 5).with_scrollback_limit(2);
+        let mut parser = Parser::new();
+        screen.process(&mut parser, b"1\r\n2\r\n3\r\n4");
+        let scrolled: Vec<String> = screen.scrollback().map(Row::text).collect();
+        assert_eq!(scrolled, ["1    ", "2    "]);
+
+        screen.process(&mut parser, b"\x1b[?1049h\x1b[Halt\r\nx\r\ny");
+        assert!(screen.alternate_screen());
+        assert_eq!(screen.contents(), "x\ny");
+        // Nothing from the alternate screen goes to the scrollback
+        assert_eq!(screen.scrollback().count(), 2);
+
+        screen.process(&mut parser, b"\x1b[?1049l");
+        assert!(!screen.alternate_screen());
+        assert_eq!(screen.contents(), "3\n4");
+        assert_eq!(screen.cursor(), (1, 1));
+    }
+
+    #[test]
+    fn test_resize_keeps_cursor_line() {
+        let mut screen = screen_after(4, 6, b"one\r\ntwo\r\nthree\r\nfour");
+        screen.resize(2, 4);
+        assert_eq!(screen.contents(), "thre\nfour");
+        assert_eq!(screen.cursor(), (1, 3));
+        assert_eq!(screen.scrollback().count(), 2);
+
+        screen.resize(3, 8);
+        assert_eq!(screen.contents(), "thre\nfour\n");
+        screen.process(&mut Parser::new(), b"\t|");
+        assert_eq!(screen.row(1).text(), "four   |");
+    }
+}
@@ -0,0 +1,147 @@
+/*
+ * gscreen - A true color command wrapper for terminal programs
+ * Copyright (C) 2025 Gamunu Balagalla <gamunu@fastcode.io>
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License along
+ * with this program; if not, write to the Free Software Foundation, Inc.,
+ * 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
+ */
+
+use anyhow::{Context, Result};
+use std::io;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+
+/// Signals gscreen can pass on to the command
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ForwardedSignal {
+    Term,
+    Hup,
+    Int,
+    Quit,
+    Usr1,
+    Usr2,
+}
+
+/// Forwarded unless configured otherwise. SIGINT is left out because the
+/// terminal already delivers Ctrl+C to the child through the PTY.
+pub const DEFAULT_FORWARDED_SIGNALS: [ForwardedSignal; 5] = [
+    ForwardedSignal::Term,
+    ForwardedSignal::Hup,
+    ForwardedSignal::Quit,
+    ForwardedSignal::Usr1,
+    ForwardedSignal::Usr2,
+];
+
+impl ForwardedSignal {
+    fn number(self) -> libc::c_int {
+        match self {
+            ForwardedSignal::Term => libc::SIGTERM,
+            ForwardedSignal::Hup => libc::SIGHUP,
+            ForwardedSignal::Int => libc::SIGINT,
+            ForwardedSignal::Quit => libc::SIGQUIT,
+            ForwardedSignal::Usr1 => libc::SIGUSR1,
+            ForwardedSignal::Usr2 => libc::SIGUSR2,
+        }
+    }
+}
+
+/// Where caught signals go, once there is a child to send them to
+pub struct SignalForwarder {
+    child: watch::Sender<Option<libc::pid_t>>,
+}
+
+impl SignalForwarder {
+    /// Send signals to this child's process group from now on, including
+    /// any caught before it was started
+    pub fn set_child(&self, pid: u32) {
+        self.child.send_replace(Some(pid as libc::pid_t));
+    }
+}
+
+/// Catch `signals` sent to gscreen and send them on to the child's process
+/// group instead. gscreen keeps running until the child exits, so the
+/// terminal gets restored and the child's status becomes ours. Call this
+/// before starting the child, so no signal can kill gscreen meanwhile.
+pub fn forward_signals(signals: &[ForwardedSignal]) -> Result<SignalForwarder> {
+    let (sender, receiver) = watch::channel(None);
+
+    for &forwarded in signals {
+        let mut stream = signal(SignalKind::from_raw(forwarded.number()))
+            .with_context(|| format!("Failed to catch {:?} for forwarding", forwarded))?;
+        let mut child = receiver.clone();
+
+        tokio::spawn(async move {
+            while stream.recv().await.is_some() {
+                let Ok(group) = child.wait_for(Option::is_some).await else {
+                    break;
+                };
+                if let Some(group) = *group {
+                    let _ = send_to_group(group, forwarded);
+                }
+            }
+        });
+    }
+
+    Ok(SignalForwarder { child: sender })
+}
+
+/// The child runs in its own session, so its pid is also its process group
+fn send_to_group(group: libc::pid_t, forwarded: ForwardedSignal) -> io::Result<()> {
+    if unsafe { libc::kill(-group, forwarded.number()) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::process::{CommandExt, ExitStatusExt};
+    use std::process::Command;
+
+    #[test]
+    fn test_signal_reaches_process_group() {
+        let mut child = Command::new("sleep")
+            .arg("10")
+            .process_group(0)
+            .spawn()
+            .unwrap();
+
+        send_to_group(child.id() as libc::pid_t, ForwardedSignal::Usr1).unwrap();
+
+        let status = child.wait().unwrap();
+        assert_eq!(status.signal(), Some(libc::SIGUSR1));
+    }
+
+    #[tokio::test]
+    async fn test_signal_before_child_is_forwarded_to_it() {
+        let forwarder = forward_signals(&[ForwardedSignal::Usr2]).unwrap();
+        unsafe { libc::kill(libc::getpid(), libc::SIGUSR2) };
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+        let mut child = Command::new("sleep")
+            .arg("10")
+            .process_group(0)
+            .spawn()
+            .unwrap();
+        forwarder.set_child(child.id());
+
+        let status = tokio::task::spawn_blocking(move || child.wait())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(status.signal(), Some(libc::SIGUSR2));
+    }
+}
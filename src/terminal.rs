@@ -19,41 +19,154 @@
 
 use anyhow::{Context, Result};
 use crossterm::terminal;
-use std::sync::Once;
+use std::io::Write;
+use std::sync::Arc;
 
-static INIT: Once = Once::new();
+use crate::probe::{self, ProbeMode, ProbeReport};
+use gscreen::__private::ChildModes;
+use gscreen::color::{ColorDepth, DefaultColors};
 
-pub fn setup_true_color_environment(debug: bool) -> Result<bool> {
-    INIT.call_once(|| {
-        // Environment setup is done here - this runs only once
-    });
+/// What the outer terminal is able to do
+#[derive(Debug, Clone, Copy)]
+pub struct Capabilities {
+    pub has_osc_support: bool,
+    pub color_depth: ColorDepth,
+    /// Whether the terminal reports the mouse in SGR (1006) encoding
+    pub sgr_mouse: bool,
+    /// The terminal's background color, if it answered OSC 11
+    pub background: Option<(u8, u8, u8)>,
+}
+
+impl Capabilities {
+    /// Correct the environment based guess with what the terminal said
+    /// about itself. A terminal that didn't answer at all tells us nothing.
+    fn apply_probe(&mut self, report: &ProbeReport) {
+        if !report.answered() {
+            return;
+        }
+        self.color_depth = if report.truecolor() {
+            ColorDepth::TrueColor
+        } else {
+            ColorDepth::Ansi256
+        };
+        self.has_osc_support = report.osc_color_queries;
+        self.sgr_mouse = report.sgr_mouse();
+        self.background = report.background;
+    }
+}
 
+/// Work out what the outer terminal supports, from the environment and,
+/// unless `probe_mode` is off, by asking the terminal. Also returns the
+/// input typed while the terminal was asked. The child's true color
+/// environment is set up by the PTY code, our own is left untouched.
+pub async fn detect_capabilities(probe_mode: ProbeMode, debug: bool) -> (Capabilities, Vec<u8>) {
     // Check terminal capabilities and get OSC support info
-    let has_osc_support = detect_and_report_color_support(debug);
+    let mut capabilities = detect_and_report_color_support(debug);
 
-    // Set environment variables for the current process
-    // (these will be inherited by child processes)
-    std::env::set_var("COLORTERM", "truecolor");
-    std::env::set_var("TERM", "xterm-256color");
-    std::env::set_var("FORCE_COLOR", "1");
-    std::env::set_var("CLICOLOR_FORCE", "1");
+    // Only a terminal on both ends can answer
+    let interactive = crossterm::tty::IsTty::is_tty(&std::io::stdin())
+        && crossterm::tty::IsTty::is_tty(&std::io::stdout());
+    if probe_mode == ProbeMode::Off || !interactive {
+        return (capabilities, Vec::new());
+    }
 
-    Ok(has_osc_support)
+    match probe::run(probe_mode, debug).await {
+        Some(report) => {
+            if debug {
+                report_probe(&report);
+            }
+            capabilities.apply_probe(&report);
+            (capabilities, report.typeahead)
+        }
+        None => (capabilities, Vec::new()),
+    }
 }
 
-pub fn restore_terminal() -> Result<()> {
+fn report_probe(report: &ProbeReport) {
+    if !report.answered() {
+        eprintln!("⚠ Terminal didn't answer the probe, going by the environment");
+        return;
+    }
+
+    let yes_no = |value: bool| if value { "yes" } else { "no" };
+    eprintln!("Terminal probe:");
+    eprintln!(
+        "  Version: {}",
+        report.version.as_deref().unwrap_or("unknown")
+    );
+    if let Some(attributes) = &report.secondary_attributes {
+        eprintln!("  Secondary attributes: {:?}", attributes);
+    }
+    eprintln!("  Truecolor capability: {}", yes_no(report.truecolor_cap));
+    eprintln!(
+        "  Truecolor SGR round trip: {}",
+        yes_no(report.truecolor_sgr)
+    );
+    eprintln!("  OSC color queries: {}", yes_no(report.osc_color_queries));
+    eprintln!("  SGR mouse: {}", yes_no(report.sgr_mouse()));
+}
+
+/// The terminal's colors as far as its `COLORFGBG` hint tells, or white on
+/// black, with the background it reported when probed
+pub fn detect_default_colors(capabilities: &Capabilities) -> DefaultColors {
+    let mut colors = std::env::var("COLORFGBG")
+        .ok()
+        .and_then(|value| DefaultColors::from_colorfgbg(&value))
+        .unwrap_or_default();
+    if let Some(background) = capabilities.background {
+        colors.background = background;
+    }
+    colors
+}
+
+/// Undo the modes the child left on in the outer terminal, through the
+/// sequences in `modes`, and leave raw mode
+pub fn restore_terminal(modes: &ChildModes) -> Result<()> {
+    let reset = modes.reset_sequence();
+    if !reset.is_empty() {
+        let mut stdout = std::io::stdout();
+        stdout
+            .write_all(&reset)
+            .and_then(|_| stdout.flush())
+            .context("Failed to reset terminal modes")?;
+    }
+
     // Disable raw mode if it was enabled
     if terminal::is_raw_mode_enabled().unwrap_or(false) {
         terminal::disable_raw_mode().context("Failed to disable raw mode")?;
     }
 
-    // We don't need to leave alternate screen since we never entered it
-    // The child process should handle its own screen mode
-
     Ok(())
 }
 
-fn detect_and_report_color_support(debug: bool) -> bool {
+/// Restores the outer terminal when dropped, and from a panic hook so the
+/// shell is usable even if gscreen dies first. Covers raw mode, which
+/// crossterm keeps track of, and every mode recorded in `modes`.
+pub struct TerminalGuard {
+    modes: Arc<ChildModes>,
+}
+
+impl TerminalGuard {
+    pub fn new(modes: Arc<ChildModes>) -> Self {
+        let hook_modes = Arc::clone(&modes);
+        let previous_hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            // Restore first, so the panic message isn't printed in raw mode
+            let _ = restore_terminal(&hook_modes);
+            previous_hook(info);
+        }));
+
+        Self { modes }
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        let _ = restore_terminal(&self.modes);
+    }
+}
+
+fn detect_and_report_color_support(debug: bool) -> Capabilities {
     // Check various environment variables that indicate color support
     let colorterm = std::env::var("COLORTERM").unwrap_or_default();
     let term = std::env::var("TERM").unwrap_or_default();
@@ -61,9 +174,8 @@ fn detect_and_report_color_support(debug: bool) -> bool {
 
     let has_truecolor = colorterm == "truecolor"
         || colorterm == "24bit"
-        || term.contains("256color")
-        || term_program == "iTerm.app"
-        || term_program == "Apple_Terminal";
+        || term.ends_with("-direct")
+        || term_program == "iTerm.app";
 
     // Check for OSC color query support
     let has_osc_support = detect_osc_support(&term, &colorterm, &term_program);
@@ -72,13 +184,13 @@ fn detect_and_report_color_support(debug: bool) -> bool {
         if has_truecolor {
             eprintln!("✓ True color support detected");
         } else {
-            eprintln!("⚠ True color support not detected, but will be forced");
+            eprintln!("⚠ True color support not detected, colors will be converted");
         }
 
         if has_osc_support {
             eprintln!("✓ OSC color query support detected");
         } else {
-            eprintln!("⚠ OSC color query support not detected - queries may appear as text");
+            eprintln!("⚠ OSC color query support not detected, gscreen will answer queries");
         }
 
         // Report current terminal info
@@ -90,7 +202,18 @@ fn detect_and_report_color_support(debug: bool) -> bool {
         }
     }
 
-    has_osc_support
+    Capabilities {
+        has_osc_support,
+        color_depth: if has_truecolor {
+            ColorDepth::TrueColor
+        } else {
+            // Every terminal we are likely to run in handles the 256-color palette
+            ColorDepth::Ansi256
+        },
+        // Assumed until a probe says otherwise, as before probing existed
+        sgr_mouse: true,
+        background: None,
+    }
 }
 
 fn detect_osc_support(term: &str, colorterm: &str, term_program: &str) -> bool {
@@ -108,3 +231,49 @@ fn detect_osc_support(term: &str, colorterm: &str, term_program: &str) -> bool {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_probe_overrides_environment_guess() {
+        let guess = Capabilities {
+            has_osc_support: false,
+            color_depth: ColorDepth::Ansi256,
+            sgr_mouse: true,
+            background: None,
+        };
+
+        let mut silent = guess;
+        silent.apply_probe(&ProbeReport::default());
+        assert_eq!(silent.color_depth, ColorDepth::Ansi256);
+        assert!(silent.sgr_mouse);
+
+        // A terminal that answered without truecolor is downgraded, whatever
+        // the environment says
+        let mut basic = Capabilities {
+            color_depth: ColorDepth::TrueColor,
+            ..guess
+        };
+        basic.apply_probe(&ProbeReport {
+            device_attributes: Some(vec![1, 2]),
+            ..ProbeReport::default()
+        });
+        assert_eq!(basic.color_depth, ColorDepth::Ansi256);
+
+        let mut probed = guess;
+        probed.apply_probe(&ProbeReport {
+            device_attributes: Some(vec![1, 2]),
+            secondary_attributes: Some(vec![0, 115, 0]),
+            truecolor_sgr: true,
+            osc_color_queries: true,
+            background: Some((0, 0, 0x40)),
+            ..ProbeReport::default()
+        });
+        assert_eq!(probed.color_depth, ColorDepth::TrueColor);
+        assert!(probed.has_osc_support);
+        assert!(!probed.sgr_mouse);
+        assert_eq!(probed.background, Some((0, 0, 0x40)));
+    }
+}
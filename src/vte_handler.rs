@@ -17,164 +17,383 @@
  * 51 Franklin Street, Fifth Floor, Boston, MA 02110-1301 USA.
  */
 
+use std::collections::HashMap;
 use std::io::{self, Write};
-use vte::{Params, Perform};
+use std::sync::Arc;
+use vte::{Params, Parser, Perform};
 
-use crate::color;
+use crate::color::{Color, ColorDepth, ColorTarget, DefaultColors};
+use crate::modes::{self, ChildModes, MouseTracking};
+
+/// Number of distinct colors remembered by the conversion cache
+const COLOR_CACHE_LIMIT: usize = 4096;
+
+const ESC: u8 = 0x1b;
+/// CAN and SUB cancel the sequence they appear in
+const CANCEL: [u8; 2] = [0x18, 0x1a];
 
 /// VTE Perform handler that processes terminal sequences and applies color conversion
-pub struct VteHandler {
-    writer: Box<dyn Write + Send>,
-    has_osc_support: bool,
+pub struct VteHandler<W = Box<dyn Write + Send>> {
+    writer: W,
+    /// The first write that failed, reported by the next flush
+    write_error: Option<io::Error>,
+    /// Leave color queries to the outer terminal, whose replies reach the
+    /// child with the rest of the input
+    forward_color_queries: bool,
+    /// Answers to color queries the outer terminal can't answer
+    default_colors: DefaultColors,
+    /// Replies to the child's queries, to be written to the PTY
+    replies: Vec<u8>,
+    color_target: ColorTarget,
+    /// Conversions done so far; programs reuse a handful of colors, and the
+    /// perceptual metrics are far too slow to run for every SGR
+    color_cache: HashMap<Color, Option<Color>>,
+    /// Modes the child turned on, shared with the input side
+    modes: Arc<ChildModes>,
+    /// Ask the outer terminal for SGR mouse reports whatever encoding the
+    /// child picked, because the input side re-encodes them
+    mouse_reencoding: bool,
+    /// Whether SGR mouse reports are currently on in the outer terminal
+    outer_sgr_mouse: bool,
+    /// Raw bytes of the sequence currently being parsed
+    pending: Vec<u8>,
+    /// Drop the next ST because the string it terminates was dropped
+    swallow_st: bool,
 }
 
-impl VteHandler {
-    pub fn new(writer: Box<dyn Write + Send>, has_osc_support: bool) -> Self {
+impl<W: Write> VteHandler<W> {
+    pub fn new(
+        writer: W,
+        forward_color_queries: bool,
+        color_target: ColorTarget,
+        modes: Arc<ChildModes>,
+    ) -> Self {
         Self {
             writer,
-            has_osc_support,
+            write_error: None,
+            forward_color_queries,
+            default_colors: DefaultColors::default(),
+            replies: Vec::new(),
+            color_target,
+            color_cache: HashMap::new(),
+            modes,
+            mouse_reencoding: false,
+            outer_sgr_mouse: false,
+            pending: Vec::new(),
+            swallow_st: false,
         }
     }
 
-    fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
-        self.writer.write_all(bytes)?;
+    /// Colors to report for OSC 10/11/12 queries answered here
+    pub fn with_default_colors(mut self, colors: DefaultColors) -> Self {
+        self.default_colors = colors;
+        self
+    }
+
+    /// Keep the outer terminal on SGR mouse reports, for an input side that
+    /// decodes mouse events and encodes them again for the child
+    pub fn with_mouse_reencoding(mut self) -> Self {
+        self.mouse_reencoding = true;
+        self
+    }
+
+    /// Feed output from the child through `parser`. Sequences that aren't
+    /// rewritten are forwarded exactly as they were received.
+    pub fn process(&mut self, parser: &mut Parser, bytes: &[u8]) {
+        for &byte in bytes {
+            self.pending.push(byte);
+            parser.advance(self, byte);
+        }
+    }
+
+    /// Take the replies to the child's queries that were answered here,
+    /// ready to be written to the PTY
+    pub fn take_replies(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.replies)
+    }
+
+    /// Flush everything written so far. Output is buffered by the writer,
+    /// so call this once per batch of input rather than per sequence.
+    /// Fails if anything written since the last flush failed.
+    pub fn flush(&mut self) -> io::Result<()> {
+        if let Some(err) = self.take_write_error() {
+            return Err(err);
+        }
         self.writer.flush()
     }
 
-    fn write_string(&mut self, s: &str) -> io::Result<()> {
-        self.write_bytes(s.as_bytes())
+    /// The first write that failed since this was last asked, if any
+    pub fn take_write_error(&mut self) -> Option<io::Error> {
+        self.write_error.take()
     }
-}
 
-impl Perform for VteHandler {
-    fn print(&mut self, c: char) {
-        let _ = self.write_string(&c.to_string());
+    pub fn get_ref(&self) -> &W {
+        &self.writer
     }
 
-    fn execute(&mut self, byte: u8) {
-        let _ = self.write_bytes(&[byte]);
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    /// The writer back, without flushing it
+    pub fn into_inner(self) -> W {
+        self.writer
     }
 
-    fn hook(&mut self, params: &Params, intermediates: &[u8], _ignore: bool, c: char) {
-        // DCS sequences - reconstruct and pass through
-        let _ = self.write_string("\x1bP");
-        self.write_params(params);
-        for &intermediate in intermediates {
-            let _ = self.write_bytes(&[intermediate]);
+    /// Write output, keeping the first error for `flush` to report
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        if let Err(err) = self.writer.write_all(bytes) {
+            self.write_error.get_or_insert(err);
         }
-        let _ = self.write_string(&c.to_string());
     }
 
-    fn put(&mut self, byte: u8) {
-        let _ = self.write_bytes(&[byte]);
+    fn write_string(&mut self, s: &str) {
+        self.write_bytes(s.as_bytes())
     }
 
-    fn unhook(&mut self) {
-        // End of DCS sequence
-        let _ = self.write_string("\x1b\\"); // ST terminator
+    /// Forward the raw bytes of the sequence that was just parsed
+    fn forward_pending(&mut self) {
+        let pending = std::mem::take(&mut self.pending);
+        self.write_bytes(&pending);
+        self.pending = pending;
+        self.pending.clear();
     }
 
-    fn osc_dispatch(&mut self, params: &[&[u8]], bell_terminated: bool) {
-        if params.is_empty() {
-            return;
+    /// Forget the raw bytes of a sequence the handler replaced
+    fn discard_pending(&mut self) {
+        self.pending.clear();
+    }
+
+    /// Strings (OSC, DCS) are ended by the ESC of an `ESC \` terminator, or
+    /// of whatever sequence follows. Keep that ESC pending so it stays part
+    /// of the next sequence, and return whether the string ended this way.
+    fn split_string_terminator(&mut self) -> bool {
+        if self.pending.last() == Some(&ESC) {
+            self.pending.pop();
+            true
+        } else {
+            false
         }
+    }
+}
 
-        let param_str = String::from_utf8_lossy(params[0]);
+impl<W: Write> Perform for VteHandler<W> {
+    fn print(&mut self, _c: char) {
+        self.forward_pending();
+    }
 
-        // Handle OSC queries for terminals that don't support them
-        if !self.has_osc_support {
-            match param_str.as_ref() {
-                "10" => {
-                    // OSC 10: Foreground color query - respond with white
-                    let _ = self.write_bytes(b"\x1b]10;rgb:ffff/ffff/ffff\x07");
-                    return;
-                }
-                "11" => {
-                    // OSC 11: Background color query - respond with black
-                    let _ = self.write_bytes(b"\x1b]11;rgb:0000/0000/0000\x07");
-                    return;
-                }
-                "12" => {
-                    // OSC 12: Cursor color query - respond with white
-                    let _ = self.write_bytes(b"\x1b]12;rgb:ffff/ffff/ffff\x07");
-                    return;
-                }
-                _ => {
-                    // For other OSC sequences, pass through normally
-                }
-            }
+    fn execute(&mut self, byte: u8) {
+        if CANCEL.contains(&byte) {
+            // The cancelled sequence goes out as it came, for the terminal
+            // to cancel in turn
+            self.forward_pending();
+        } else if self.pending.len() > 1 && self.pending[0] == ESC {
+            // A control inside an unfinished sequence is executed on its own,
+            // the sequence itself may still need to be rewritten
+            self.pending.pop();
+            self.write_bytes(&[byte]);
+        } else {
+            self.forward_pending();
         }
+    }
+
+    fn hook(&mut self, _params: &Params, _intermediates: &[u8], _ignore: bool, _c: char) {
+        // DCS sequences - pass through
+        self.forward_pending();
+    }
 
-        // For supported terminals or non-query OSC sequences, pass through
-        let _ = self.write_bytes(b"\x1b]");
+    fn put(&mut self, _byte: u8) {
+        self.forward_pending();
+    }
 
-        // Write parameters with proper semicolon separation
-        for (i, param) in params.iter().enumerate() {
-            if i > 0 {
-                let _ = self.write_bytes(b";");
-            }
-            let _ = self.write_bytes(param);
+    fn unhook(&mut self) {
+        // End of DCS sequence
+        let esc_terminated = self.split_string_terminator();
+        self.forward_pending();
+        if esc_terminated {
+            self.pending.push(ESC);
         }
+    }
+
+    fn osc_dispatch(&mut self, params: &[&[u8]], bell_terminated: bool) {
+        let esc_terminated = self.split_string_terminator();
 
-        // Always use the terminator that was actually received
-        if bell_terminated {
-            let _ = self.write_bytes(b"\x07"); // BEL (^G)
+        // Answer color queries the outer terminal won't, everything else
+        // passes through
+        let reply = if self.forward_color_queries {
+            None
         } else {
-            let _ = self.write_bytes(b"\x1b\\"); // ST
+            self.color_query_reply(params, bell_terminated)
+        };
+
+        match reply {
+            Some(reply) => {
+                self.discard_pending();
+                self.replies.extend_from_slice(reply.as_bytes());
+                self.swallow_st = esc_terminated;
+            }
+            None => self.forward_pending(),
+        }
+
+        if esc_terminated {
+            self.pending.push(ESC);
         }
     }
 
-    fn csi_dispatch(&mut self, params: &Params, intermediates: &[u8], _ignore: bool, c: char) {
+    fn csi_dispatch(&mut self, params: &Params, intermediates: &[u8], ignore: bool, c: char) {
         match c {
-            'm' => {
-                // SGR (Select Graphic Rendition) - handle colors specially
+            // SGR (Select Graphic Rendition) - handle colors specially.
+            // Private variants like `CSI > 4 ; 2 m` are not SGR, and an SGR
+            // that overflowed vte's parameter limit is left alone.
+            'm' if !ignore && intermediates.is_empty() => {
                 self.handle_sgr_sequence(params);
             }
+            'h' | 'l' if intermediates == b"?" => {
+                self.handle_private_mode(params, c);
+            }
             _ => {
                 // All other CSI sequences, pass through unchanged
-
-                let _ = self.write_string("\x1b[");
-                self.write_params(params);
-                for &intermediate in intermediates {
-                    let _ = self.write_bytes(&[intermediate]);
-                }
-                let _ = self.write_string(&c.to_string());
+                self.forward_pending();
             }
         }
     }
 
     fn esc_dispatch(&mut self, intermediates: &[u8], _ignore: bool, byte: u8) {
+        if std::mem::take(&mut self.swallow_st) && intermediates.is_empty() && byte == b'\\' {
+            // ST of a string we replaced
+            self.discard_pending();
+            return;
+        }
+
+        // DECKPAM/DECKPNM - remember the keypad mode, then pass through
+        if intermediates.is_empty() && matches!(byte, b'=' | b'>') {
+            self.modes.set_keypad_application(byte == b'=');
+        }
+
         // ESC sequences - pass through
-        let _ = self.write_bytes(b"\x1b");
-        let _ = self.write_bytes(intermediates);
-        let _ = self.write_bytes(&[byte]);
+        self.forward_pending();
     }
 }
 
-impl VteHandler {
-    fn write_params(&mut self, params: &Params) {
-        for (i, param) in params.iter().enumerate() {
-            if i > 0 {
-                let _ = self.write_string(";");
+impl<W: Write> VteHandler<W> {
+    /// Reply to an OSC 4 palette or OSC 10/11/12 dynamic color query, with
+    /// the same terminator it used. `None` for anything that also sets a
+    /// color, and for palette queries when the palette isn't known, which
+    /// are left to the outer terminal.
+    fn color_query_reply(&self, params: &[&[u8]], bell_terminated: bool) -> Option<String> {
+        let terminator = if bell_terminated { "\x07" } else { "\x1b\\" };
+        let (command, args) = params.split_first()?;
+        if args.is_empty() {
+            return None;
+        }
+
+        let mut reply = String::new();
+        match *command {
+            b"4" => {
+                // Pairs of palette index and `?`
+                let palette = self.color_target.palette?;
+                if args.len() % 2 != 0 {
+                    return None;
+                }
+                for pair in args.chunks(2) {
+                    if pair[1] != b"?" {
+                        return None;
+                    }
+                    let index: u8 = std::str::from_utf8(pair[0]).ok()?.parse().ok()?;
+                    let spec = rgb_spec(palette.rgb(index));
+                    reply.push_str(&format!("\x1b]4;{};{}{}", index, spec, terminator));
+                }
             }
-            for (j, &value) in param.iter().enumerate() {
-                if j > 0 {
-                    let _ = self.write_string(":");
+            b"10" | b"11" | b"12" => {
+                // Each further `?` asks for the next color, so `10;?;?`
+                // queries both the foreground and the background
+                let first: usize = std::str::from_utf8(command).ok()?.parse().ok()?;
+                for (offset, arg) in args.iter().enumerate() {
+                    if *arg != b"?" {
+                        return None;
+                    }
+                    let code = first + offset;
+                    let rgb = match code {
+                        10 => self.default_colors.foreground,
+                        11 => self.default_colors.background,
+                        12 => self.default_colors.cursor,
+                        _ => return None,
+                    };
+                    reply.push_str(&format!("\x1b]{};{}{}", code, rgb_spec(rgb), terminator));
                 }
-                let _ = self.write_string(&value.to_string());
             }
+            _ => return None,
         }
+        Some(reply)
     }
 
-    fn handle_sgr_sequence(&mut self, params: &Params) {
-        if params.is_empty() {
-            // Reset
-            let _ = self.write_string("\x1b[0m");
+    /// DECSET/DECRST - remember the modes, then pass them through, except
+    /// mouse encodings when the outer terminal is kept on SGR reports
+    fn handle_private_mode(&mut self, params: &Params, c: char) {
+        for param in params.iter() {
+            self.modes.set_private_mode(param[0], c == 'h');
+        }
+
+        if !self.mouse_reencoding {
+            self.forward_pending();
             return;
         }
 
-        // Convert params to a vector for easier manipulation
+        let kept: Vec<String> = params
+            .iter()
+            .filter(|param| !modes::is_mouse_encoding(param[0]))
+            .map(format_param)
+            .collect();
+        if kept.len() == params.len() {
+            self.forward_pending();
+        } else {
+            self.discard_pending();
+            if !kept.is_empty() {
+                let sequence = format!("\x1b[?{}{}", kept.join(";"), c);
+                self.write_bytes(sequence.as_bytes());
+            }
+        }
+
+        let want_sgr = self.modes.mouse_tracking() != MouseTracking::Off;
+        if want_sgr != self.outer_sgr_mouse {
+            self.outer_sgr_mouse = want_sgr;
+            let sequence: &[u8] = if want_sgr {
+                b"\x1b[?1006h"
+            } else {
+                b"\x1b[?1006l"
+            };
+            self.write_bytes(sequence);
+        }
+    }
+
+    fn handle_sgr_sequence(&mut self, params: &Params) {
         let param_vec: Vec<&[u16]> = params.iter().collect();
+        let converted = self.convert_sgr(&param_vec);
+
+        let unchanged = converted.len() == param_vec.len()
+            && converted
+                .iter()
+                .zip(&param_vec)
+                .all(|(new, old)| *new == format_param(old));
+        if unchanged {
+            // Nothing changed, keep the original spelling
+            self.forward_pending();
+            return;
+        }
+
+        self.discard_pending();
+        if !converted.is_empty() {
+            self.write_string(&format!("\x1b[{}m", converted.join(";")));
+        }
+        // Otherwise everything was dropped; an empty SGR would reset all
+        // attributes, so write nothing at all
+    }
+
+    /// Convert the parameters of one SGR sequence for the target terminal,
+    /// returning the new parameters. Parameters that don't change keep their
+    /// original spelling.
+    fn convert_sgr(&mut self, param_vec: &[&[u16]]) -> Vec<String> {
+        let mut converted: Vec<String> = Vec::with_capacity(param_vec.len());
         let mut i = 0;
 
         while i < param_vec.len() {
@@ -185,213 +404,618 @@ impl VteHandler {
             }
 
             match param[0] {
-                38 => {
-                    // Foreground color
-                    if let Some((converted, consumed)) =
-                        self.handle_color_params_vec(&param_vec, i, false)
-                    {
-                        let _ = self.write_string(&converted);
-                        i += consumed;
-                    } else {
-                        // Pass through unchanged
-                        let _ = self.write_string("\x1b[38");
-                        for param in param_vec.iter().skip(i + 1) {
-                            if !param.is_empty() {
-                                let _ = self.write_string(&format!(";{}", param[0]));
+                38 | 48 | 58 => {
+                    // Foreground, background or underline color
+                    let slot = ColorSlot::from_sgr(param[0]);
+                    match parse_extended_color(param_vec, i) {
+                        Some((color, consumed)) => {
+                            match self.convert_color(color, slot) {
+                                Some(sgr) if sgr == extended_color_sgr(color, slot) => {
+                                    // Unchanged, possibly in colon form
+                                    converted.extend(
+                                        param_vec[i..i + consumed].iter().map(|p| format_param(p)),
+                                    );
+                                }
+                                sgr => converted.extend(sgr),
                             }
+                            i += consumed;
+                        }
+                        None => {
+                            // We can't tell how many parameters a malformed
+                            // color spans, so pass the rest through unchanged
+                            converted.extend(param_vec[i..].iter().map(|p| format_param(p)));
+                            break;
                         }
-                        let _ = self.write_string("m");
-                        return;
                     }
                 }
-                48 => {
-                    // Background color
-                    if let Some((converted, consumed)) =
-                        self.handle_color_params_vec(&param_vec, i, true)
-                    {
-                        let _ = self.write_string(&converted);
-                        i += consumed;
+                30..=37 | 40..=47 | 90..=97 | 100..=107 => {
+                    // Basic colors, only rewritten for 8-color and mono targets
+                    converted.extend(self.convert_basic_color(param[0]).map(|c| c.to_string()));
+                    i += 1;
+                }
+                4 if param.len() > 1 => {
+                    // Styled underline (4:0 none, 4:1 single, 4:2 double,
+                    // 4:3 curly, 4:4 dotted, 4:5 dashed)
+                    let sgr = if self.supports_underline_extensions() {
+                        format!("4:{}", param[1])
+                    } else if param[1] == 0 {
+                        "24".to_string()
                     } else {
-                        // Pass through unchanged
-                        let _ = self.write_string("\x1b[48");
-                        for param in param_vec.iter().skip(i + 1) {
-                            if !param.is_empty() {
-                                let _ = self.write_string(&format!(";{}", param[0]));
-                            }
-                        }
-                        let _ = self.write_string("m");
-                        return;
+                        // Fall back to a plain underline
+                        "4".to_string()
+                    };
+                    converted.push(sgr);
+                    i += 1;
+                }
+                59 => {
+                    // Default underline color
+                    if self.supports_underline_extensions() {
+                        converted.push("59".to_string());
                     }
+                    i += 1;
                 }
                 _ => {
                     // Other SGR parameters, pass through
-                    let _ = self.write_string(&format!("\x1b[{}m", param[0]));
+                    converted.push(format_param(param));
                     i += 1;
                 }
             }
         }
+
+        converted
     }
 
-    fn handle_color_params_vec(
-        &mut self,
-        param_vec: &[&[u16]],
-        start_idx: usize,
-        is_background: bool,
-    ) -> Option<(String, usize)> {
-        if start_idx + 1 >= param_vec.len() {
-            return None;
+    /// SGR parameters for an extended color converted to the target, or
+    /// `None` if the target can't show it
+    fn convert_color(&mut self, color: Color, slot: ColorSlot) -> Option<String> {
+        if self.color_cache.len() >= COLOR_CACHE_LIMIT {
+            // Something like a gradient demo, don't grow without bound
+            self.color_cache.clear();
         }
+        let target = self.color_target;
+        let converted = *self
+            .color_cache
+            .entry(color)
+            .or_insert_with(|| target.convert(color));
+        self.color_sgr(converted?, slot)
+    }
 
-        let color_type_param = param_vec[start_idx + 1];
-        if color_type_param.is_empty() {
-            return None;
-        }
+    /// Whether the target understands underline colors and styles. Terminals
+    /// limited to 16 colors or fewer predate both extensions.
+    fn supports_underline_extensions(&self) -> bool {
+        matches!(
+            self.color_target.depth,
+            ColorDepth::TrueColor | ColorDepth::Ansi256
+        )
+    }
 
-        match color_type_param[0] {
-            2 => {
-                // True color: 38;2;R;G;B or 48;2;R;G;B
-                if start_idx + 4 < param_vec.len() {
-                    let r_param = param_vec[start_idx + 2];
-                    let g_param = param_vec[start_idx + 3];
-                    let b_param = param_vec[start_idx + 4];
-
-                    if !r_param.is_empty() && !g_param.is_empty() && !b_param.is_empty() {
-                        let r = r_param[0];
-                        let g = g_param[0];
-                        let b = b_param[0];
-
-                        if r <= 255 && g <= 255 && b <= 255 {
-                            let color_idx = color::rgb_to_256color(r as u8, g as u8, b as u8);
-                            let converted = if is_background {
-                                format!("\x1b[48;5;{}m", color_idx)
-                            } else {
-                                format!("\x1b[38;5;{}m", color_idx)
-                            };
-                            return Some((converted, 5)); // Consumed 5 params: 38/48, 2, R, G, B
-                        }
-                    }
+    /// SGR parameters selecting an already converted color, or `None` if the
+    /// target can't display it in this slot
+    fn color_sgr(&self, color: Color, slot: ColorSlot) -> Option<String> {
+        let base = match slot {
+            ColorSlot::Foreground => 30,
+            ColorSlot::Background => 40,
+            ColorSlot::Underline if self.supports_underline_extensions() => 50,
+            ColorSlot::Underline => return None,
+        };
+        let sgr = match color {
+            Color::Indexed(index)
+                if index < 16
+                    && matches!(
+                        self.color_target.depth,
+                        ColorDepth::Ansi16 | ColorDepth::Ansi8
+                    ) =>
+            {
+                if index < 8 {
+                    format!("{}", base + index as u16)
+                } else {
+                    format!("{}", base + 60 + (index - 8) as u16)
                 }
-                None
             }
-            5 => {
-                // 256-color: 38;5;N or 48;5;N - pass through unchanged
-                if start_idx + 2 < param_vec.len() {
-                    let color_param = param_vec[start_idx + 2];
-                    if !color_param.is_empty() && color_param[0] <= 255 {
-                        let converted = if is_background {
-                            format!("\x1b[48;5;{}m", color_param[0])
-                        } else {
-                            format!("\x1b[38;5;{}m", color_param[0])
-                        };
-                        return Some((converted, 3)); // Consumed 3 params: 38/48, 5, N
-                    }
+            color => extended_color_sgr(color, slot),
+        };
+        Some(sgr)
+    }
+
+    /// Convert a basic SGR color (30-37, 40-47, 90-97, 100-107) for the
+    /// target depth. Returns `None` when the color should be dropped.
+    fn convert_basic_color(&self, code: u16) -> Option<u16> {
+        match (self.color_target.depth, code) {
+            (ColorDepth::Mono, 30..=37 | 40..=47 | 90..=97 | 100..=107) => None,
+            (ColorDepth::Ansi8, 90..=97 | 100..=107) => {
+                // The same as bright colors given by index, which go through
+                // the palette if there is one
+                let base = if code < 100 { 30 } else { 40 };
+                match self
+                    .color_target
+                    .convert(Color::Indexed((code % 10) as u8 + 8))
+                {
+                    Some(Color::Indexed(index)) => Some(base + index as u16),
+                    _ => Some(code - 60),
                 }
-                None
             }
-            _ => None,
+            _ => Some(code),
+        }
+    }
+}
+
+/// A color in the 16 bits per channel form xterm reports
+fn rgb_spec((r, g, b): (u8, u8, u8)) -> String {
+    format!(
+        "rgb:{:02x}{:02x}/{:02x}{:02x}/{:02x}{:02x}",
+        r, r, g, g, b, b
+    )
+}
+
+/// Format a parameter with its subparameters, e.g. `4:3`
+fn format_param(param: &[u16]) -> String {
+    param
+        .iter()
+        .map(|value| value.to_string())
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// The semicolon form of an extended color, e.g. `38;5;N` or `48;2;R;G;B`
+fn extended_color_sgr(color: Color, slot: ColorSlot) -> String {
+    let code = match slot {
+        ColorSlot::Foreground => 38,
+        ColorSlot::Background => 48,
+        ColorSlot::Underline => 58,
+    };
+    match color {
+        Color::Indexed(index) => format!("{};5;{}", code, index),
+        Color::Rgb(r, g, b) => format!("{};2;{};{};{}", code, r, g, b),
+    }
+}
+
+/// Which attribute an extended color applies to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ColorSlot {
+    Foreground,
+    Background,
+    Underline,
+}
+
+impl ColorSlot {
+    fn from_sgr(code: u16) -> Self {
+        match code {
+            48 => ColorSlot::Background,
+            58 => ColorSlot::Underline,
+            _ => ColorSlot::Foreground,
         }
     }
 }
 
+/// Parse an extended color (38, 48, 58) starting at `start_idx`, returning the
+/// color and how many parameters it spans. Both the semicolon form
+/// (`38;2;R;G;B`, `38;5;N`) and the ITU T.416 colon form (`38:2:CS:R:G:B`,
+/// `38:2:R:G:B`, `38:5:N`) are understood.
+pub(crate) fn parse_extended_color(
+    param_vec: &[&[u16]],
+    start_idx: usize,
+) -> Option<(Color, usize)> {
+    let param = param_vec[start_idx];
+    if param.len() > 1 {
+        // Colon form, the whole color lives in a single parameter. vte
+        // reports an omitted color space id (38:2::R:G:B) as 0.
+        let color = match param[1..] {
+            [5, index, ..] => Color::Indexed(u8::try_from(index).ok()?),
+            [2, r, g, b] | [2, _, r, g, b, ..] => Color::Rgb(
+                u8::try_from(r).ok()?,
+                u8::try_from(g).ok()?,
+                u8::try_from(b).ok()?,
+            ),
+            _ => return None,
+        };
+        return Some((color, 1));
+    }
+
+    let value_at = |offset: usize| -> Option<u8> {
+        let value = *param_vec.get(start_idx + offset)?.first()?;
+        u8::try_from(value).ok()
+    };
+
+    match param_vec.get(start_idx + 1)?.first()? {
+        // True color: 38;2;R;G;B or 48;2;R;G;B, consumes 5 params
+        2 => Some((Color::Rgb(value_at(2)?, value_at(3)?, value_at(4)?), 5)),
+        // 256-color: 38;5;N or 48;5;N, consumes 3 params
+        5 => Some((Color::Indexed(value_at(2)?), 3)),
+        _ => None,
+    }
+}
+
 /// InputVteHandler processes terminal responses (terminal -> application)
 /// Unlike the output handler, this one passes sequences through without color conversion
+#[derive(Default)]
 pub struct InputVteHandler {
-    writer: Box<dyn Write + Send>,
+    /// Bytes ready to be written to the child
+    output: Vec<u8>,
+    /// Raw bytes of the sequence currently being parsed
+    pending: Vec<u8>,
 }
 
 impl InputVteHandler {
-    pub fn new(writer: Box<dyn Write + Send>) -> Self {
-        Self { writer }
+    pub fn new() -> Self {
+        Self::default()
     }
 
-    fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
-        self.writer.write_all(bytes)?;
-        self.writer.flush()
+    /// Feed input through `parser`, forwarding every byte exactly as received
+    pub fn process(&mut self, parser: &mut Parser, bytes: &[u8]) {
+        for &byte in bytes {
+            self.pending.push(byte);
+            parser.advance(self, byte);
+        }
+        // Nothing is rewritten on this side, so partial sequences can go out
+        // now instead of waiting for the rest (e.g. a lone ESC keypress)
+        self.forward_pending();
     }
 
-    fn write_string(&mut self, s: &str) -> io::Result<()> {
-        self.write_bytes(s.as_bytes())
+    /// Take the bytes processed so far, ready to be written to the child
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
+    fn forward_pending(&mut self) {
+        self.output.append(&mut self.pending);
     }
 }
 
 impl Perform for InputVteHandler {
-    fn print(&mut self, c: char) {
-        let _ = self.write_string(&c.to_string());
+    fn print(&mut self, _c: char) {
+        self.forward_pending();
     }
 
-    fn execute(&mut self, byte: u8) {
-        let _ = self.write_bytes(&[byte]);
+    fn execute(&mut self, _byte: u8) {
+        self.forward_pending();
     }
 
-    fn hook(&mut self, params: &Params, intermediates: &[u8], _ignore: bool, c: char) {
-        // DCS sequences - reconstruct and pass through unchanged
-        let _ = self.write_string("\x1bP");
-        self.write_params(params);
-        for &intermediate in intermediates {
-            let _ = self.write_bytes(&[intermediate]);
-        }
-        let _ = self.write_string(&c.to_string());
+    fn hook(&mut self, _params: &Params, _intermediates: &[u8], _ignore: bool, _c: char) {
+        // DCS sequences - pass through unchanged
+        self.forward_pending();
     }
 
-    fn put(&mut self, byte: u8) {
-        let _ = self.write_bytes(&[byte]);
+    fn put(&mut self, _byte: u8) {
+        self.forward_pending();
     }
 
     fn unhook(&mut self) {
-        // End of DCS sequence
-        let _ = self.write_string("\x1b\\"); // ST terminator
+        self.forward_pending();
     }
 
-    fn osc_dispatch(&mut self, params: &[&[u8]], bell_terminated: bool) {
+    fn osc_dispatch(&mut self, _params: &[&[u8]], _bell_terminated: bool) {
         // OSC sequences - pass through unchanged (no color conversion)
-        let _ = self.write_bytes(b"\x1b]");
+        self.forward_pending();
+    }
 
-        // Write parameters with proper semicolon separation
-        for (i, param) in params.iter().enumerate() {
-            if i > 0 {
-                let _ = self.write_bytes(b";");
-            }
-            let _ = self.write_bytes(param);
+    fn csi_dispatch(&mut self, _params: &Params, _intermediates: &[u8], _ignore: bool, _c: char) {
+        // All CSI sequences pass through unchanged (no color processing for input)
+        self.forward_pending();
+    }
+
+    fn esc_dispatch(&mut self, _intermediates: &[u8], _ignore: bool, _byte: u8) {
+        // ESC sequences - pass through unchanged
+        self.forward_pending();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::{ColorMetric, Palette};
+    use std::sync::{Arc, Mutex};
+
+    /// Writer that keeps everything written to it for inspection
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
         }
 
-        // Use the terminator that was actually received
-        if bell_terminated {
-            let _ = self.write_bytes(b"\x07"); // BEL (^G)
-        } else {
-            let _ = self.write_bytes(b"\x1b\\"); // ST
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
         }
     }
 
-    fn csi_dispatch(&mut self, params: &Params, intermediates: &[u8], _ignore: bool, c: char) {
-        // All CSI sequences pass through unchanged (no color processing for input)
-        let _ = self.write_string("\x1b[");
-        self.write_params(params);
-        for &intermediate in intermediates {
-            let _ = self.write_bytes(&[intermediate]);
+    /// Run `input` through the output pipeline for the given color depth
+    fn convert_bytes(input: &[u8], depth: ColorDepth) -> Vec<u8> {
+        let buffer = SharedBuffer::default();
+        let target = ColorTarget {
+            depth,
+            metric: ColorMetric::Fast,
+            ..ColorTarget::default()
+        };
+        let mut handler = VteHandler::new(
+            Box::new(buffer.clone()),
+            true,
+            target,
+            Arc::new(ChildModes::default()),
+        );
+        let mut parser = Parser::new();
+        handler.process(&mut parser, input);
+        let output = buffer.0.lock().unwrap().clone();
+        output
+    }
+
+    fn convert(input: &[u8], depth: ColorDepth) -> String {
+        String::from_utf8(convert_bytes(input, depth)).unwrap()
+    }
+
+    #[test]
+    fn test_unmodified_sequences_pass_through_byte_exact() {
+        let overflow = format!("\x1b[{}m", "1;".repeat(40));
+        let cases: [&[u8]; 16] = [
+            // Private markers
+            b"\x1b[?1049h\x1b[?2004l",
+            b"\x1b[>1u\x1b[<u\x1b[?u",
+            b"\x1b[>4;2m",
+            // Trailing empty parameter and more parameters than vte keeps
+            b"\x1b[1;m",
+            overflow.as_bytes(),
+            // 8-bit C1 introducer, which vte leaves as text, so a control
+            // inside stays where it was
+            b"\x9b1mtext",
+            b"\x9b38;2;1;2\x083m",
+            // A sequence cancelled by CAN or SUB
+            b"\x1b[31\x18hello",
+            b"\x1b[38;2;1\x1a2;3m",
+            // Strings with either terminator
+            b"\x1b]0;title\x1b\\",
+            b"\x1b]8;;https://example.com\x07link\x1b]8;;\x07",
+            b"\x1bP+q5463\x1b\\",
+            b"\x1b_Gf=100;AAAA\x1b\\",
+            // Invalid UTF-8
+            b"\xff\xfeabc\xc3(x",
+            // Colon form that needs no conversion
+            b"\x1b[38:2::10:20:30;4:3m",
+            // An SGR with nothing to convert
+            b"\x1b[0;1;38;5;196m",
+        ];
+
+        for input in cases {
+            let depth = if input.starts_with(b"\x1b[38:2") {
+                ColorDepth::TrueColor
+            } else {
+                ColorDepth::Ansi256
+            };
+            assert_eq!(
+                convert_bytes(input, depth),
+                input,
+                "{:?}",
+                String::from_utf8_lossy(input)
+            );
         }
-        let _ = self.write_string(&c.to_string());
     }
 
-    fn esc_dispatch(&mut self, intermediates: &[u8], _ignore: bool, byte: u8) {
-        // ESC sequences - pass through unchanged
-        let _ = self.write_bytes(b"\x1b");
-        let _ = self.write_bytes(intermediates);
-        let _ = self.write_bytes(&[byte]);
+    #[test]
+    fn test_recorded_session_round_trips_in_truecolor() {
+        let recording = include_bytes!("../benches/data/vim-truecolor-scroll.raw");
+        assert_eq!(convert_bytes(recording, ColorDepth::TrueColor), recording);
     }
-}
 
-impl InputVteHandler {
-    fn write_params(&mut self, params: &Params) {
-        for (i, param) in params.iter().enumerate() {
-            if i > 0 {
-                let _ = self.write_string(";");
-            }
-            for (j, &value) in param.iter().enumerate() {
-                if j > 0 {
-                    let _ = self.write_string(":");
-                }
-                let _ = self.write_string(&value.to_string());
-            }
+    #[test]
+    fn test_color_queries_answered_to_the_child() {
+        let buffer = SharedBuffer::default();
+        let colors = DefaultColors {
+            foreground: (0xcd, 0xd6, 0xf4),
+            background: (0x1e, 0x1e, 0x2e),
+            cursor: (0xf5, 0xe0, 0xdc),
+        };
+        let mut palette = Palette::XTERM;
+        palette.colors[1] = (0xf3, 0x8b, 0xa8);
+        let target = ColorTarget {
+            palette: Some(palette),
+            ..ColorTarget::default()
+        };
+        let mut handler = VteHandler::new(
+            Box::new(buffer.clone()),
+            false,
+            target,
+            Arc::new(ChildModes::default()),
+        )
+        .with_default_colors(colors);
+        let mut parser = Parser::new();
+
+        let cases: [(&[u8], &str); 4] = [
+            (b"\x1b]11;?\x07", "\x1b]11;rgb:1e1e/1e1e/2e2e\x07"),
+            (
+                b"\x1b]10;?;?\x1b\\",
+                "\x1b]10;rgb:cdcd/d6d6/f4f4\x1b\\\x1b]11;rgb:1e1e/1e1e/2e2e\x1b\\",
+            ),
+            (
+                b"\x1b]4;1;?;255;?\x07",
+                "\x1b]4;1;rgb:f3f3/8b8b/a8a8\x07\x1b]4;255;rgb:eeee/eeee/eeee\x07",
+            ),
+            // Setting a color isn't a query
+            (b"\x1b]11;#000000\x07", ""),
+        ];
+        for (query, reply) in cases {
+            handler.process(&mut parser, query);
+            assert_eq!(
+                String::from_utf8(handler.take_replies()).unwrap(),
+                reply,
+                "{:?}",
+                query
+            );
+        }
+
+        // Only the color setting reaches the screen
+        let output = buffer.0.lock().unwrap().clone();
+        assert_eq!(output, b"\x1b]11;#000000\x07");
+    }
+
+    #[test]
+    fn test_palette_queries_forwarded_without_a_palette() {
+        let buffer = SharedBuffer::default();
+        let mut handler = VteHandler::new(
+            Box::new(buffer.clone()),
+            false,
+            ColorTarget::default(),
+            Arc::new(ChildModes::default()),
+        );
+        let mut parser = Parser::new();
+
+        handler.process(&mut parser, b"\x1b]4;1;?\x07\x1b]11;?\x07");
+        assert_eq!(
+            String::from_utf8(handler.take_replies()).unwrap(),
+            "\x1b]11;rgb:0000/0000/0000\x07"
+        );
+        let output = buffer.0.lock().unwrap().clone();
+        assert_eq!(output, b"\x1b]4;1;?\x07");
+    }
+
+    #[test]
+    fn test_color_queries_forwarded_when_terminal_answers() {
+        let buffer = SharedBuffer::default();
+        let mut handler = VteHandler::new(
+            Box::new(buffer.clone()),
+            true,
+            ColorTarget::default(),
+            Arc::new(ChildModes::default()),
+        );
+        let mut parser = Parser::new();
+
+        handler.process(&mut parser, b"\x1b]11;?\x1b\\x");
+        assert!(handler.take_replies().is_empty());
+        let output = buffer.0.lock().unwrap().clone();
+        assert_eq!(output, b"\x1b]11;?\x1b\\x");
+    }
+
+    #[test]
+    fn test_private_modes_tracked_and_forwarded() {
+        let modes = Arc::new(ChildModes::default());
+        let buffer = SharedBuffer::default();
+        let mut handler = VteHandler::new(
+            Box::new(buffer.clone()),
+            true,
+            ColorTarget::default(),
+            Arc::clone(&modes),
+        );
+        let mut parser = Parser::new();
+
+        handler.process(&mut parser, b"\x1b[?1049;2004h");
+        assert!(modes.bracketed_paste());
+        handler.process(&mut parser, b"\x1b[?2004l\x1b=");
+        assert!(!modes.bracketed_paste());
+        assert_eq!(modes.reset_sequence(), b"\x1b[?1049l\x1b>");
+
+        let output = buffer.0.lock().unwrap().clone();
+        assert_eq!(output, b"\x1b[?1049;2004h\x1b[?2004l\x1b=");
+    }
+
+    #[test]
+    fn test_mouse_encoding_kept_on_sgr_when_reencoding() {
+        let buffer = SharedBuffer::default();
+        let mut handler = VteHandler::new(
+            Box::new(buffer.clone()),
+            true,
+            ColorTarget::default(),
+            Arc::new(ChildModes::default()),
+        )
+        .with_mouse_reencoding();
+        let mut parser = Parser::new();
+
+        handler.process(&mut parser, b"\x1b[?1002;1005h\x1b[?1015h\x1b[?1002l");
+
+        let output = buffer.0.lock().unwrap().clone();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "\x1b[?1002h\x1b[?1006h\x1b[?1002l\x1b[?1006l"
+        );
+    }
+
+    #[test]
+    fn test_control_inside_sgr_is_executed_first() {
+        assert_eq!(
+            convert(b"\x1b[3\n8;2;255;0;0m", ColorDepth::Ansi256),
+            "\n\x1b[38;5;196m"
+        );
+    }
+
+    #[test]
+    fn test_sgr_emitted_as_single_sequence() {
+        let cases = [
+            // Attributes and a converted color stay in one sequence
+            (
+                "\x1b[1;4;38;2;10;20;30m",
+                ColorDepth::Ansi256,
+                "\x1b[1;4;38;5;16m",
+            ),
+            (
+                "\x1b[1;4;38;2;10;20;30m",
+                ColorDepth::TrueColor,
+                "\x1b[1;4;38;2;10;20;30m",
+            ),
+            (
+                "\x1b[38:2::255:0:0;1;48;5;21m",
+                ColorDepth::Ansi256,
+                "\x1b[38;5;196;1;48;5;21m",
+            ),
+            // Everything after the colors is kept
+            (
+                "\x1b[38;5;196;48;5;21;3;9m",
+                ColorDepth::Ansi256,
+                "\x1b[38;5;196;48;5;21;3;9m",
+            ),
+            (
+                "\x1b[0;1;38;5;196;48;2;255;0;0;4:3m",
+                ColorDepth::Ansi16,
+                "\x1b[0;1;91;101;4m",
+            ),
+            (
+                "\x1b[4:3;58;2;255;0;0m",
+                ColorDepth::Ansi256,
+                "\x1b[4:3;58;5;196m",
+            ),
+            // Dropped colors leave the other attributes alone
+            ("\x1b[1;31;48;5;21m", ColorDepth::Mono, "\x1b[1m"),
+            ("\x1b[31m", ColorDepth::Mono, ""),
+            ("\x1b[92;102m", ColorDepth::Ansi8, "\x1b[32;42m"),
+            // Malformed colors and the rest of the list pass through
+            ("\x1b[1;38;7;3m", ColorDepth::Ansi256, "\x1b[1;38;7;3m"),
+            ("\x1b[m", ColorDepth::Ansi256, "\x1b[m"),
+        ];
+
+        for (input, depth, expected) in cases {
+            assert_eq!(
+                convert(input.as_bytes(), depth),
+                expected,
+                "{:?} at depth {}",
+                input,
+                depth
+            );
+        }
+    }
+
+    type ColorCase<'a> = (&'a [&'a [u16]], Option<(Color, usize)>);
+
+    #[test]
+    fn test_parse_extended_color_forms() {
+        let cases: [ColorCase; 8] = [
+            // Semicolon forms
+            (
+                &[&[38], &[2], &[10], &[20], &[30]],
+                Some((Color::Rgb(10, 20, 30), 5)),
+            ),
+            (&[&[48], &[5], &[196]], Some((Color::Indexed(196), 3))),
+            // Colon forms, with and without a color space id
+            (
+                &[&[38, 2, 0, 10, 20, 30]],
+                Some((Color::Rgb(10, 20, 30), 1)),
+            ),
+            (&[&[38, 2, 10, 20, 30]], Some((Color::Rgb(10, 20, 30), 1))),
+            (&[&[48, 5, 42]], Some((Color::Indexed(42), 1))),
+            // Truncated or out of range
+            (&[&[38], &[2], &[10], &[20]], None),
+            (&[&[38, 5, 256]], None),
+            (&[&[38, 2, 10]], None),
+        ];
+
+        for (params, expected) in cases {
+            assert_eq!(parse_extended_color(params, 0), expected, "{:?}", params);
         }
     }
 }